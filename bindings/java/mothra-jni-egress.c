@@ -74,4 +74,8 @@ JNIEXPORT void JNICALL Java_net_p2p_mothra_SendRPC (JNIEnv *jenv, jclass jcls, j
     if (data) (*jenv)->ReleaseByteArrayElements(jenv, jdata, data, 0);
     if (peer) (*jenv)->ReleaseByteArrayElements(jenv, jpeer, peer, 0);
     if (method) (*jenv)->ReleaseByteArrayElements(jenv, jmethod, method, 0);
+}
+
+JNIEXPORT void JNICALL Java_net_p2p_mothra_ValidationComplete (JNIEnv *jenv, jclass jcls, jlong jmessage_id){
+    libp2p_validation_complete((unsigned long long)jmessage_id);
 }
\ No newline at end of file
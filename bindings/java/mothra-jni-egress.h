@@ -9,11 +9,13 @@ extern "C" {
 JNIEXPORT void JNICALL Java_net_p2p_mothra_Start(JNIEnv *, jclass, jobjectArray);
 JNIEXPORT void JNICALL Java_net_p2p_mothra_SendGossip(JNIEnv *, jclass, jbyteArray, jbyteArray);
 JNIEXPORT void JNICALL Java_net_p2p_mothra_SendRPC (JNIEnv *, jclass, jbyteArray, jint, jbyteArray, jbyteArray);
+JNIEXPORT void JNICALL Java_net_p2p_mothra_ValidationComplete (JNIEnv *, jclass, jlong);
 
 extern void libp2p_start(char**, int);
 extern void libp2p_send_gossip(jbyte*, int, jbyte*, int);
 extern void libp2p_send_rpc_request(jbyte*, int, jbyte*, int, jbyte*, int);
 extern void libp2p_send_rpc_response(jbyte*, int, jbyte*, int, jbyte*, int);
+extern void libp2p_validation_complete(unsigned long long);
 
 #ifdef __cplusplus
 }
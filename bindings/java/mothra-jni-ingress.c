@@ -39,7 +39,7 @@ void discovered_peer(const unsigned char* peer, int peer_length) {
     }
 }
 
-void receive_gossip(const unsigned char* topic, int topic_length, unsigned char* data, int data_length) {
+void receive_gossip(const unsigned char* topic, int topic_length, unsigned char* data, int data_length, unsigned long long message_id) {
     JNIEnv *jenv;
     jint rs = (*jvm)->AttachCurrentThread(jvm, (void**)&jenv, NULL);
     assert (rs == JNI_OK);
@@ -60,12 +60,12 @@ void receive_gossip(const unsigned char* topic, int topic_length, unsigned char*
         if(!jdata || !jtopic){
             detach(jenv);
         }
-        receivegossip_method = (*jenv)->GetStaticMethodID(jenv, mothra_class, "ReceiveGossip", "([B[B)V");
+        receivegossip_method = (*jenv)->GetStaticMethodID(jenv, mothra_class, "ReceiveGossip", "([B[BJ)V");
         if(!receivegossip_method){
-            printf("JNI Error: GetStaticMethodID was unable to find method: ReceiveGossip with signature: ([B[B)V\n");
+            printf("JNI Error: GetStaticMethodID was unable to find method: ReceiveGossip with signature: ([B[BJ)V\n");
             detach(jenv);
         }
-        (*jenv)->CallStaticVoidMethod(jenv, mothra_class, receivegossip_method, jtopic, jdata);
+        (*jenv)->CallStaticVoidMethod(jenv, mothra_class, receivegossip_method, jtopic, jdata, (jlong)message_id);
     }
 }
 
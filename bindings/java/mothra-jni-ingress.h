@@ -9,7 +9,7 @@ extern "C" {
 JNIEXPORT void JNICALL Java_net_p2p_mothra_Init(JNIEnv*,jclass);
 
 void discovered_peer(const unsigned char*, int);
-void receive_gossip(const unsigned char*, int, unsigned char*, int);
+void receive_gossip(const unsigned char*, int, unsigned char*, int, unsigned long long);
 void receive_rpc(const unsigned char*, int, int, const unsigned char*, int, unsigned char*, int);
 
 void detach(JNIEnv* );
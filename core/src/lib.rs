@@ -13,7 +13,7 @@ use std::cell::RefMut;
 use cast::i16;
 use cast::i8;
 use slog::{info, debug, warn, o, Drain};
-use libp2p_wrapper::{Message,GOSSIP,RPC,DISCOVERY};
+use libp2p_wrapper::{Message,GOSSIP,RPC,DISCOVERY,VALIDATION};
 use mothra_api::api;
 
 #[derive(Debug)]
@@ -51,7 +51,7 @@ macro_rules! get_tx {
 
 extern {
     fn discovered_peer(peer_c_uchar: *const c_uchar, peer_length: i16);
-    fn receive_gossip(topic_c_uchar: *const c_uchar, topic_length: i16, data_c_uchar: *mut c_uchar, data_length: i16);
+    fn receive_gossip(topic_c_uchar: *const c_uchar, topic_length: i16, data_c_uchar: *mut c_uchar, data_length: i16, message_id: u64);
     fn receive_rpc(method_c_uchar: *const c_uchar, method_length: i16, req_resp: i16, peer_c_uchar: *const c_uchar, peer_length: i16, data_c_uchar: *mut c_uchar, data_length: i16);
 }
 
@@ -97,7 +97,7 @@ pub extern fn libp2p_start(args_c_char: *mut *mut c_char, length: isize) {
                         let data_length = i16(network_message.value.len()).unwrap();
                         let data = network_message.value.as_mut_ptr();
                         unsafe {
-                            receive_gossip(topic, topic_length, data, data_length);
+                            receive_gossip(topic, topic_length, data, data_length, network_message.id);
                         }
                     } else if network_message.category == RPC.to_string(){
                         //debug!(log, "received RPC from peer: {:?} method: {:?} req/resp: {:?}", network_message.peer,network_message.command,network_message.req_resp);
@@ -155,4 +155,13 @@ pub extern fn libp2p_send_rpc_response(method_c_uchar: *mut c_uchar, method_leng
     let mut data = unsafe { std::slice::from_raw_parts_mut(data_c_uchar, data_length) };
     let rpc_data = Message::new(RPC.to_string(),method.to_string(),1,peer.to_string(),data.to_vec());
     get_tx!().as_mut().unwrap().send(rpc_data);
-}
\ No newline at end of file
+}
+
+/// Reports that the application has finished validating the gossip message with the given id,
+/// freeing its validation slot.
+#[no_mangle]
+pub extern fn libp2p_validation_complete(message_id: u64) {
+    let mut validation = Message::new(VALIDATION.to_string(),Default::default(),Default::default(),Default::default(),Vec::new());
+    validation.id = message_id;
+    get_tx!().as_mut().unwrap().send(validation);
+}
@@ -1,25 +1,52 @@
+use crate::clock;
+use crate::chunking::{
+    self, ChunkReassembler, CHUNK_HEADER_LEN, CHUNK_REASSEMBLY_TIMEOUT, MAX_PARTIAL_BYTES,
+    MAX_PARTIAL_BYTES_PER_SOURCE,
+};
 use crate::config::*;
-use crate::discovery::Discovery;
-use crate::rpc::{RPCEvent, RPCMessage, RPC};
+use crate::discovery::{
+    ConnectionLogEntry, ConnectionTag, DialExpectations, DisconnectReason, Discovery,
+};
+use crate::peer_selector::{MostRecentlyActive, PeerSelector};
+use crate::rpc::{
+    ErrorMessage, RPCErrorResponse, RPCEvent, RPCMessage, RPCRequest, RPCResponse, RequestId, RPC,
+};
 use crate::{error, NetworkConfig};
 use crate::{Topic, TopicHash};
 use futures::prelude::*;
+use futures::sync::oneshot;
 use libp2p::{
     core::identity::Keypair,
     discv5::Discv5Event,
     gossipsub::{Gossipsub, GossipsubEvent},
     identify::{Identify, IdentifyEvent},
-    ping::{Ping, PingConfig, PingEvent},
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
     swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess},
+    multiaddr::Protocol,
     tokio_io::{AsyncRead, AsyncWrite},
-    NetworkBehaviour, PeerId,
+    Multiaddr, NetworkBehaviour, PeerId,
 };
-use slog::{o, debug};
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use slog::{o, debug, info, warn};
+use std::net::IpAddr;
 use std::num::NonZeroU32;
-use std::time::Duration;
-use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use tokio_timer::Delay;
 
 const MAX_IDENTIFY_ADDRESSES: usize = 20;
+/// The maximum number of gossip messages held back while waiting for a validation slot. The
+/// service stops reading from the network while any are held, so only messages already received
+/// can queue here. Any beyond the limit are dropped.
+const MAX_PENDING_VALIDATIONS: usize = 1024;
+/// The maximum number of published messages held waiting for subscribers. Further messages are
+/// dropped.
+const MAX_HELD_PUBLISHES: usize = 1024;
+/// The protocol id negotiated by the gossipsub version in use. Its protocol id is fixed rather
+/// than configurable.
+const GOSSIPSUB_PROTOCOL_ID: &str = "/meshsub/1.0.0";
 
 /// Builds the network behaviour that manages the core protocols of eth2.
 /// This core behaviour is managed by `Behaviour` which adds peer management to all core
@@ -41,6 +68,105 @@ pub struct Behaviour<TSubstream: AsyncRead + AsyncWrite> {
     #[behaviour(ignore)]
     /// The events generated by this behaviour to be consumed in the swarm poll.
     events: Vec<BehaviourEvent>,
+    #[behaviour(ignore)]
+    /// Selects which events are generated.
+    event_filter: EventFilter,
+    #[behaviour(ignore)]
+    /// The protocols each connected peer reported through identify, deduplicated and sorted.
+    peer_protocols: HashMap<PeerId, Vec<String>>,
+    #[behaviour(ignore)]
+    /// Application-provided progress markers, such as the highest slot gossiped, per connected
+    /// peer.
+    peer_progress: HashMap<PeerId, u64>,
+    #[behaviour(ignore)]
+    /// Waiting for peers to report the address they see us at, so our ENR can be updated.
+    observed_addr_requests: HashMap<PeerId, Vec<oneshot::Sender<Multiaddr>>>,
+    #[behaviour(ignore)]
+    /// The topics each connected peer has told us it subscribes to.
+    peer_topics: HashMap<PeerId, HashSet<TopicHash>>,
+    #[behaviour(ignore)]
+    /// When each connected peer told us it subscribes to each of its topics.
+    peer_subscribed_at: HashMap<(PeerId, TopicHash), Instant>,
+    #[behaviour(ignore)]
+    /// How often gossipsub adds subscribers to the mesh of each topic we subscribe to.
+    heartbeat_interval: Duration,
+    #[behaviour(ignore)]
+    /// The topics we are subscribed to.
+    subscriptions: HashSet<TopicHash>,
+    #[behaviour(ignore)]
+    /// The maximum number of topics we may subscribe to, if limited.
+    max_subscriptions: Option<usize>,
+    #[behaviour(ignore)]
+    /// Messages are held until this many peers subscribe to their topic, if set.
+    min_publish_peers: Option<usize>,
+    #[behaviour(ignore)]
+    /// How long a message is held waiting for subscribers.
+    publish_hold_timeout: Duration,
+    #[behaviour(ignore)]
+    /// Messages held for more subscribers, with when they expire.
+    held_publishes: VecDeque<(Topic, Vec<u8>, Instant)>,
+    #[behaviour(ignore)]
+    /// Wakes the behaviour when the oldest held message expires.
+    hold_expiry: Option<Delay>,
+    #[behaviour(ignore)]
+    /// Whether peers subscribing to topics we do not support are disconnected.
+    strict_topic_policy: bool,
+    #[behaviour(ignore)]
+    /// Whether the application is syncing, during which only gossip on allowed topics is
+    /// delivered.
+    syncing: bool,
+    #[behaviour(ignore)]
+    /// Topics whose gossip is delivered while syncing.
+    sync_allowed_topics: HashSet<TopicHash>,
+    #[behaviour(ignore)]
+    /// Maximum inbound message sizes for topics with their own limit.
+    topic_max_sizes: HashMap<TopicHash, usize>,
+    #[behaviour(ignore)]
+    /// Maximum inbound message size for topics without their own limit.
+    default_max_size: usize,
+    #[behaviour(ignore)]
+    /// The maximum number of messages out for application validation at once, if limited.
+    max_concurrent_validations: Option<usize>,
+    #[behaviour(ignore)]
+    /// The messages currently out for application validation, by message id, with the topics
+    /// whose slots each holds.
+    validations_in_flight: HashMap<u64, Vec<TopicHash>>,
+    #[behaviour(ignore)]
+    /// The maximum number of messages on a topic out for application validation at once.
+    topic_validation_concurrency: HashMap<TopicHash, usize>,
+    #[behaviour(ignore)]
+    /// The number of messages on each limited topic currently out for application validation.
+    topic_validations_in_flight: HashMap<TopicHash, usize>,
+    #[behaviour(ignore)]
+    /// The number of received gossip messages dropped, by reason.
+    dropped_messages: HashMap<DropReason, u64>,
+    #[behaviour(ignore)]
+    /// Messages waiting for a validation slot.
+    pending_validations: VecDeque<BehaviourEvent>,
+    #[behaviour(ignore)]
+    /// The id given to the next gossip message handed to the application.
+    next_message_id: u64,
+    #[behaviour(ignore)]
+    /// Topics whose gossip is read as chunks of larger messages.
+    chunked_topics: HashSet<TopicHash>,
+    #[behaviour(ignore)]
+    /// Chunks of large messages waiting for the rest of their message.
+    reassembler: ChunkReassembler,
+    #[behaviour(ignore)]
+    /// The id of the next chunked message we publish.
+    next_chunked_id: u64,
+    #[behaviour(ignore)]
+    /// The item sent in answer to `SampleRecent` requests, provided by the application.
+    recent_item: Option<Vec<u8>>,
+    #[behaviour(ignore)]
+    /// Chooses the peers returned by `sample_peers`.
+    peer_selector: Box<dyn PeerSelector>,
+    #[behaviour(ignore)]
+    /// Log publishes and RPC sends rather than performing them.
+    dry_run: bool,
+    /// The multiplexer negotiated with each connected peer, recorded by the transport.
+    #[behaviour(ignore)]
+    multiplexers: NegotiatedMultiplexers,
     /// Logger for behaviour actions.
     #[behaviour(ignore)]
     log: slog::Logger,
@@ -49,10 +175,14 @@ pub struct Behaviour<TSubstream: AsyncRead + AsyncWrite> {
 impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
     pub fn new(
         local_key: &Keypair,
+        gossip_key: &Keypair,
         net_conf: &NetworkConfig,
+        dial_expectations: DialExpectations,
+        multiplexers: NegotiatedMultiplexers,
         log: &slog::Logger,
     ) -> error::Result<Self> {
-        let local_peer_id = local_key.public().clone().into_peer_id();
+        // gossipsub messages are authored by the gossip key, which defaults to the local key
+        let gossip_author = gossip_key.public().into_peer_id();
         let behaviour_log = log.new(o!());
         let ping_config = PingConfig::new()
             .with_timeout(Duration::from_secs(30))
@@ -66,14 +196,74 @@ impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
             local_key.public(),
         );
 
-
         Ok(Behaviour {
-            serenity_rpc: RPC::new(log),
-            gossipsub: Gossipsub::new(local_peer_id.clone(), net_conf.gs_config.clone()),
-            discovery: Discovery::new(local_key, net_conf, log)?,
+            serenity_rpc: RPC::new(
+                net_conf.max_open_substreams,
+                net_conf.serving_budget,
+                net_conf.rpc_timeouts.clone(),
+                log,
+            ),
+            gossipsub: Gossipsub::new(gossip_author, net_conf.gs_config.clone()),
+            discovery: Discovery::new(local_key, net_conf, dial_expectations, log)?,
             ping: Ping::new(ping_config),
             identify,
             events: Vec::new(),
+            event_filter: EventFilter::default(),
+            peer_protocols: HashMap::new(),
+            peer_progress: HashMap::new(),
+            observed_addr_requests: HashMap::new(),
+            peer_topics: HashMap::new(),
+            peer_subscribed_at: HashMap::new(),
+            heartbeat_interval: net_conf.gs_config.heartbeat_interval,
+            subscriptions: HashSet::new(),
+            max_subscriptions: net_conf.max_subscriptions,
+            recent_item: None,
+            peer_selector: Box::new(MostRecentlyActive),
+            dry_run: net_conf.dry_run,
+            multiplexers,
+            min_publish_peers: net_conf.min_publish_peers,
+            publish_hold_timeout: net_conf.publish_hold_timeout,
+            held_publishes: VecDeque::new(),
+            hold_expiry: None,
+            strict_topic_policy: net_conf.strict_topic_policy,
+            syncing: false,
+            sync_allowed_topics: net_conf
+                .sync_allowed_topics
+                .iter()
+                .map(|topic| Topic::new(topic.clone()).no_hash())
+                .collect(),
+            topic_max_sizes: net_conf
+                .topic_max_sizes
+                .iter()
+                .map(|(topic, size)| (Topic::new(topic.clone()).no_hash(), *size))
+                .collect(),
+            default_max_size: net_conf.gs_config.max_transmit_size,
+            max_concurrent_validations: net_conf.max_concurrent_validations,
+            validations_in_flight: HashMap::new(),
+            topic_validation_concurrency: net_conf
+                .topic_validation_concurrency
+                .iter()
+                .map(|(topic, max)| (Topic::new(topic.clone()).no_hash(), *max))
+                .collect(),
+            topic_validations_in_flight: HashMap::new(),
+            pending_validations: VecDeque::new(),
+            next_message_id: 1,
+            dropped_messages: HashMap::new(),
+            chunked_topics: net_conf
+                .chunked_topics
+                .iter()
+                .map(|topic| Topic::new(topic.clone()).no_hash())
+                .collect(),
+            reassembler: ChunkReassembler::new(
+                CHUNK_REASSEMBLY_TIMEOUT,
+                MAX_PARTIAL_BYTES,
+                MAX_PARTIAL_BYTES_PER_SOURCE,
+            ),
+            // start from the clock so ids are not reused by a restarted node
+            next_chunked_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or(0),
             log: behaviour_log,
         })
     }
@@ -87,17 +277,33 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<GossipsubE
         match event {
             GossipsubEvent::Message(gs_msg) => {
                 //debug!(self.log, "Received GossipEvent"; "msg" => format!("{:?}", gs_msg));
-
-                //let msg = PubsubMessage::from_topics(&gs_msg.topics, gs_msg.data);
-
-                self.events.push(BehaviourEvent::PubsubMessage {
-                    source: gs_msg.source,
-                    topics: gs_msg.topics,
-                    message: gs_msg.data
-                });
+                self.handle_gossip(gs_msg.source, gs_msg.topics, gs_msg.data);
+            }
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                if self.strict_topic_policy && !self.is_supported_topic(&topic) {
+                    warn!(self.log, "Peer subscribed to an unsupported topic, disconnecting"; "peer_id" => format!("{}", peer_id), "topic" => topic.as_str());
+                    self.discovery
+                        .disconnect_peer(peer_id, DisconnectReason::UnsupportedTopic);
+                    return;
+                }
+                self.peer_topics
+                    .entry(peer_id.clone())
+                    .or_insert_with(HashSet::new)
+                    .insert(topic.clone());
+                self.peer_subscribed_at
+                    .entry((peer_id, topic.clone()))
+                    .or_insert_with(clock::now);
+                self.release_held_publishes(&topic);
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                self.peer_subscribed_at.remove(&(peer_id.clone(), topic.clone()));
+                if let Some(topics) = self.peer_topics.get_mut(&peer_id) {
+                    topics.remove(&topic);
+                    if topics.is_empty() {
+                        self.peer_topics.remove(&peer_id);
+                    }
+                }
             }
-            GossipsubEvent::Subscribed { .. } => {}
-            GossipsubEvent::Unsubscribed { .. } => {}
         }
     }
 }
@@ -108,13 +314,32 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<RPCMessage
     fn inject_event(&mut self, event: RPCMessage) {
         match event {
             RPCMessage::PeerDialed(peer_id) => {
-                self.events.push(BehaviourEvent::PeerDialed(peer_id))
+                // discovery drops connections it rejects as soon as they are made, and they are
+                // never reported as disconnected
+                if !self.discovery.take_rejected_dial(&peer_id) {
+                    self.add_event(BehaviourEvent::PeerDialed(peer_id))
+                }
             }
             RPCMessage::PeerDisconnected(peer_id) => {
-                self.events.push(BehaviourEvent::PeerDisconnected(peer_id))
+                self.forget_peer(&peer_id);
+                self.add_event(BehaviourEvent::PeerDisconnected(peer_id))
             }
             RPCMessage::RPC(peer_id, rpc_event) => {
-                self.events.push(BehaviourEvent::RPC(peer_id, rpc_event))
+                match rpc_event {
+                    RPCEvent::Request(..) | RPCEvent::Response(..) => {
+                        self.discovery.record_activity(&peer_id)
+                    }
+                    RPCEvent::Error(..) => {}
+                }
+                if let RPCEvent::Request(id, RPCRequest::SampleRecent) = rpc_event {
+                    self.respond_sample_recent(peer_id, id);
+                    return;
+                }
+                self.add_event(BehaviourEvent::RPC(peer_id, rpc_event))
+            }
+            RPCMessage::InboundRefused { peer_id, request } => {
+                debug!(self.log, "Refused inbound RPC request at the open substream limit"; "peer_id" => format!("{}", peer_id), "request" => format!("{:?}", request));
+                self.add_event(BehaviourEvent::InboundRefused { peer_id, request })
             }
         }
     }
@@ -123,18 +348,220 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<RPCMessage
 impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<PingEvent>
     for Behaviour<TSubstream>
 {
-    fn inject_event(&mut self, _event: PingEvent) {
-        // not interested in ping responses at the moment.
+    fn inject_event(&mut self, event: PingEvent) {
+        // round trip times inform which peers to evict
+        if let Ok(PingSuccess::Ping { rtt }) = event.result {
+            self.discovery.record_latency(&event.peer, rtt);
+        }
     }
 }
 
 impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
+    /// Handles a gossip message from gossipsub, handing it to the application unless it is
+    /// dropped.
+    fn handle_gossip(&mut self, source: PeerId, topics: Vec<TopicHash>, data: Vec<u8>) {
+        // gossipsub does not say which peer forwarded a message, so only its author is
+        // credited with the activity, when directly connected
+        self.discovery.record_activity(&source);
+        let is_probe = topics.contains(&probe_topic().no_hash());
+        let filtered = if is_probe {
+            !self.event_filter.probe_received
+        } else {
+            !self.event_filter.pubsub_message
+        };
+        if filtered {
+            self.record_drop(DropReason::Filtered);
+            return;
+        }
+        if self.syncing
+            && !topics
+                .iter()
+                .any(|topic| self.sync_allowed_topics.contains(topic))
+        {
+            self.record_drop(DropReason::Syncing);
+            return;
+        }
+
+        let size = data.len();
+        if let Some(topic) = topics
+            .iter()
+            .find(|topic| size > self.max_message_size(topic))
+        {
+            debug!(self.log, "Dropping oversized gossip message"; "topic" => topic.as_str(), "size" => size, "source" => format!("{}", source));
+            self.record_drop(DropReason::Oversized);
+            return;
+        }
+
+        if is_probe {
+            match probe_latency(&data) {
+                Some(latency) => self.push_event(BehaviourEvent::ProbeReceived {
+                    origin: source,
+                    latency,
+                }),
+                None => {
+                    debug!(self.log, "Dropping malformed probe"; "source" => format!("{}", source));
+                    self.record_drop(DropReason::MalformedProbe);
+                }
+            }
+            return;
+        }
+
+        if let Some(topic) = topics
+            .iter()
+            .find(|topic| self.chunked_topics.contains(topic))
+            .cloned()
+        {
+            match chunking::decode_chunk(&data) {
+                Some(chunk) => {
+                    if let Some(data) = self.reassembler.add(&source, chunk) {
+                        let id = self.take_message_id();
+                        self.queue_for_validation(BehaviourEvent::ChunkedMessageComplete {
+                            id,
+                            source,
+                            topic,
+                            data,
+                        });
+                    }
+                }
+                None => {
+                    debug!(self.log, "Dropping gossip message that is not a chunk"; "topic" => topic.as_str(), "source" => format!("{}", source));
+                    self.record_drop(DropReason::MalformedChunk);
+                }
+            }
+            return;
+        }
+
+        let id = self.take_message_id();
+        let message = PubsubMessage::from_topics(&topics, data);
+        self.queue_for_validation(BehaviourEvent::PubsubMessage {
+            id,
+            source,
+            topics,
+            message,
+        });
+    }
+
+    /// Counts a received gossip message dropped before reaching the application.
+    pub fn record_drop(&mut self, reason: DropReason) {
+        *self.dropped_messages.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Drops what we know of a peer that has disconnected.
+    fn forget_peer(&mut self, peer_id: &PeerId) {
+        self.peer_protocols.remove(peer_id);
+        self.peer_topics.remove(peer_id);
+        self.peer_subscribed_at
+            .retain(|(subscriber, _), _| subscriber != peer_id);
+        self.peer_progress.remove(peer_id);
+        self.observed_addr_requests.remove(peer_id);
+        self.multiplexers.forget(peer_id);
+    }
+
+    /// Queues an event for the swarm, unless the event filter excludes it.
+    fn add_event(&mut self, event: BehaviourEvent) {
+        if self.event_filter.allows(&event) {
+            self.push_event(event);
+        }
+    }
+
+    /// Queues an event for the swarm.
+    fn push_event(&mut self, event: BehaviourEvent) {
+        self.events.push(event);
+    }
+
+    /// Whether we subscribe to a topic, or recognise it as one we could subscribe to.
+    fn is_supported_topic(&self, topic: &TopicHash) -> bool {
+        self.subscriptions.contains(topic) || is_known_topic(topic)
+    }
+
+    /// The maximum accepted size of an inbound message on a topic.
+    fn max_message_size(&self, topic: &TopicHash) -> usize {
+        self.topic_max_sizes
+            .get(topic)
+            .cloned()
+            .unwrap_or(self.default_max_size)
+    }
+
+    /// Gives a received gossip message its id.
+    fn take_message_id(&mut self) -> u64 {
+        let id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        id
+    }
+
+    /// Hands a gossip message to the application, unless a validation limit has been reached,
+    /// in which case it waits for a free slot.
+    fn queue_for_validation(&mut self, event: BehaviourEvent) {
+        if !self.validations_limited() {
+            self.push_event(event);
+        } else if self.has_validation_slot(validation_topics(&event)) {
+            self.start_validation(event);
+        } else if self.pending_validations.len() >= MAX_PENDING_VALIDATIONS {
+            debug!(self.log, "Validation queue full, dropping gossip message");
+            self.record_drop(DropReason::ValidationQueueFull);
+        } else {
+            self.pending_validations.push_back(event);
+        }
+    }
+
+    /// Whether the number of messages out for validation is limited.
+    fn validations_limited(&self) -> bool {
+        self.max_concurrent_validations.is_some() || !self.topic_validation_concurrency.is_empty()
+    }
+
+    /// Whether a message on the given topics can be handed out for validation now.
+    fn has_validation_slot(&self, topics: &[TopicHash]) -> bool {
+        if let Some(max) = self.max_concurrent_validations {
+            if self.validations_in_flight.len() >= max {
+                return false;
+            }
+        }
+        topics.iter().all(|topic| match self.topic_validation_concurrency.get(topic) {
+            Some(max) => self.topic_validations_in_flight.get(topic).cloned().unwrap_or(0) < *max,
+            None => true,
+        })
+    }
+
+    /// Hands a message to the application, taking a validation slot on each of its topics until
+    /// the application completes its id.
+    fn start_validation(&mut self, event: BehaviourEvent) {
+        if let Some(id) = validation_id(&event) {
+            let topics = validation_topics(&event).to_vec();
+            for topic in &topics {
+                if self.topic_validation_concurrency.contains_key(topic) {
+                    *self
+                        .topic_validations_in_flight
+                        .entry(topic.clone())
+                        .or_insert(0) += 1;
+                }
+            }
+            self.validations_in_flight.insert(id, topics);
+        }
+        self.push_event(event);
+    }
+
+    /// Takes the next event waiting for the swarm. Lets the service drain the messages handed
+    /// out for validation without polling the network.
+    pub fn take_event(&mut self) -> Option<BehaviourEvent> {
+        if self.events.is_empty() {
+            return None;
+        }
+        Some(self.events.remove(0))
+    }
+
     /// Consumes the events list when polled.
     fn poll<TBehaviourIn>(
         &mut self,
     ) -> Async<NetworkBehaviourAction<TBehaviourIn, BehaviourEvent>> {
-        if !self.events.is_empty() {
-            return Async::Ready(NetworkBehaviourAction::GenerateEvent(self.events.remove(0)));
+        for (claimed, derived) in self.discovery.take_peer_id_mismatches() {
+            warn!(self.log, "Dialed peer presented a different identity"; "expected" => format!("{}", claimed), "actual" => format!("{}", derived));
+            self.add_event(BehaviourEvent::PeerIdMismatch { claimed, derived });
+        }
+
+        self.poll_hold_expiry();
+
+        if let Some(event) = self.take_event() {
+            return Async::Ready(NetworkBehaviourAction::GenerateEvent(event));
         }
 
         Async::NotReady
@@ -147,8 +574,31 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<IdentifyEv
     fn inject_event(&mut self, event: IdentifyEvent) {
         match event {
             IdentifyEvent::Identified {
-                peer_id, mut info, ..
+                peer_id,
+                mut info,
+                observed_addr,
             } => {
+                // only addresses reported by peers asked to refresh ours are trusted for our ENR
+                if let Some(requests) = self.observed_addr_requests.remove(&peer_id) {
+                    let ip = observed_addr.iter().find_map(|protocol| match protocol {
+                        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+                        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+                        _ => None,
+                    });
+                    match ip {
+                        Some(ip) => {
+                            if let Err(e) = self.discovery.update_external_ip(ip) {
+                                warn!(self.log, "Could not update external address"; "error" => e);
+                            }
+                        }
+                        None => {
+                            warn!(self.log, "Observed address has no IP"; "address" => format!("{}", observed_addr))
+                        }
+                    }
+                    for request in requests {
+                        let _ = request.send(observed_addr.clone());
+                    }
+                }
                 if info.listen_addrs.len() > MAX_IDENTIFY_ADDRESSES {
                     debug!(
                         self.log,
@@ -162,6 +612,12 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<IdentifyEv
                 "Listening Addresses" => format!("{:?}", info.listen_addrs),
                 "Protocols" => format!("{:?}", info.protocols)
                 );
+                self.discovery
+                    .add_identified_addresses(&peer_id, info.listen_addrs);
+                let mut protocols = info.protocols;
+                protocols.sort();
+                protocols.dedup();
+                self.peer_protocols.insert(peer_id, protocols);
             }
             IdentifyEvent::Error { .. } => {}
             IdentifyEvent::SendBack { .. } => {}
@@ -181,25 +637,414 @@ impl<TSubstream: AsyncRead + AsyncWrite> NetworkBehaviourEventProcess<Discv5Even
 impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
     /* Pubsub behaviour functions */
 
-    /// Subscribes to a gossipsub topic.
+    /// Subscribes to a gossipsub topic. Fails if already subscribed, or if the subscription
+    /// limit has been reached.
     pub fn subscribe(&mut self, topic: Topic) -> bool {
-        self.gossipsub.subscribe(topic)
+        let topic_hash = topic.no_hash();
+        if let Some(max) = self.max_subscriptions {
+            if self.subscriptions.len() >= max && !self.subscriptions.contains(&topic_hash) {
+                warn!(self.log, "Subscription limit reached, not subscribing"; "topic" => topic_hash.as_str(), "limit" => max);
+                return false;
+            }
+        }
+        if !self.gossipsub.subscribe(topic) {
+            return false;
+        }
+        self.subscriptions.insert(topic_hash);
+        true
+    }
+
+    /// The topics we are subscribed to.
+    pub fn subscriptions(&self) -> &HashSet<TopicHash> {
+        &self.subscriptions
+    }
+
+    /// The full topic string, including its encoding suffix, that a topic hash was sent on, for
+    /// topics we subscribe to or a connected peer has announced. Topics are not hashed, so the
+    /// string is carried in the hash itself.
+    pub fn topic_string(&self, topic: &TopicHash) -> Option<String> {
+        let known = self.subscriptions.contains(topic)
+            || self.peer_topics.values().any(|topics| topics.contains(topic));
+        if known {
+            Some(topic.as_str().to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Exports our gossip subscriptions so that another node, such as a standby, can be brought
+    /// up subscribed to the same topics.
+    pub fn export_gossip_state(&self) -> GossipState {
+        GossipState {
+            topics: self
+                .subscriptions
+                .iter()
+                .map(|topic| topic.as_str().to_string())
+                .collect(),
+        }
+    }
+
+    /// Subscribes to every topic in an exported gossip state, returning the number of new
+    /// subscriptions. Existing subscriptions are kept. Subnets are not advertised in our ENR, as
+    /// they are by `subscribe_attnet`.
+    pub fn import_gossip_state(&mut self, state: GossipState) -> usize {
+        state
+            .topics
+            .into_iter()
+            .filter(|topic| self.subscribe(Topic::new(topic.clone())))
+            .count()
+    }
+
+    /// The topics a connected peer has announced subscriptions to.
+    pub fn peer_topics(&self, peer_id: &PeerId) -> Option<&HashSet<TopicHash>> {
+        self.peer_topics.get(peer_id)
+    }
+
+    /// Every topic a connected peer has announced a subscription to, whether or not we are
+    /// subscribed to it ourselves.
+    pub fn observed_topics(&self) -> HashSet<TopicHash> {
+        self.peer_topics.values().flatten().cloned().collect()
+    }
+
+    /// The gossipsub protocol ids this node negotiates with peers.
+    ///
+    /// The gossipsub version in use hard-codes its protocol id, and `GossipsubConfig` has no
+    /// setting for it, so this is always `/meshsub/1.0.0` whatever `gs_config` holds.
+    pub fn gossipsub_protocol_ids(&self) -> Vec<String> {
+        vec![GOSSIPSUB_PROTOCOL_ID.to_string()]
+    }
+
+    /// Publishes a probe carrying the current time on the diagnostic probe topic. Nodes with
+    /// probes enabled report its propagation latency when it arrives.
+    pub fn publish_probe(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or(0);
+        if let Err(e) = self.publish(vec![probe_topic()], now.to_be_bytes().to_vec()) {
+            debug!(self.log, "Probe reached no peers"; "error" => format!("{}", e));
+        }
+    }
+
+    /// Publishes a message too large for a single gossip message by splitting it into chunks of
+    /// at most `chunk_size` bytes. Receiving nodes reassemble the chunks, producing a
+    /// `ChunkedMessageComplete` event once all have arrived. The topic must be one of the
+    /// configured `chunked_topics`, on this node and its peers.
+    pub fn publish_chunked(
+        &mut self,
+        topic: Topic,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Result<(), String> {
+        if !self.chunked_topics.contains(&topic.no_hash()) {
+            return Err(format!("{} is not a chunked topic", topic.no_hash().as_str()));
+        }
+        let max_size = self.max_message_size(&topic.no_hash());
+        if CHUNK_HEADER_LEN + chunk_size > max_size {
+            return Err(format!(
+                "Chunk size of {} bytes exceeds the maximum of {} bytes",
+                chunk_size,
+                max_size.saturating_sub(CHUNK_HEADER_LEN)
+            ));
+        }
+        let id = self.next_chunked_id;
+        let chunks = chunking::split(id, data, chunk_size)?;
+        self.next_chunked_id = self.next_chunked_id.wrapping_add(1);
+        // every chunk is published, so a message that reaches no peers now is still cached
+        let mut result = Ok(());
+        for chunk in chunks {
+            let published = self.publish(vec![topic.clone()], chunk);
+            if result.is_ok() {
+                result = published.map_err(|e| e.to_string());
+            }
+        }
+        result
+    }
+
+    /// Subscribes to an attestation subnet topic and advertises the subnet in our ENR.
+    pub fn subscribe_attnet(&mut self, subnet_id: u64) -> bool {
+        if !self.subscribe(attnet_topic(subnet_id)) {
+            return false;
+        }
+        if let Err(e) = self.discovery.update_attnets(subnet_id, true) {
+            warn!(self.log, "Could not advertise attestation subnet"; "subnet_id" => subnet_id, "error" => e);
+        }
+        true
+    }
+
+    /// Unsubscribes from an attestation subnet topic and removes it from our ENR.
+    pub fn unsubscribe_attnet(&mut self, subnet_id: u64) -> bool {
+        let topic = attnet_topic(subnet_id);
+        let topic_hash = topic.no_hash();
+        if !self.gossipsub.unsubscribe(topic) {
+            return false;
+        }
+        self.subscriptions.remove(&topic_hash);
+        if let Err(e) = self.discovery.update_attnets(subnet_id, false) {
+            warn!(self.log, "Could not withdraw attestation subnet"; "subnet_id" => subnet_id, "error" => e);
+        }
+        true
     }
 
     /// Publishes a message on the pubsub (gossipsub) behaviour.
-    pub fn publish(&mut self, topics: Vec<Topic>, message: Vec<u8>) {
+    ///
+    /// Fails if the message could reach no peer on one of its topics. It is still published to
+    /// every topic, so gossipsub keeps it in its message cache. Fails with `PublishError::Held`
+    /// if it is instead held for a topic until `min_publish_peers` subscribe to it.
+    pub fn publish(&mut self, topics: Vec<Topic>, message: Vec<u8>) -> Result<(), PublishError> {
+        if self.dry_run {
+            let topics: Vec<String> = topics.iter().map(|topic| topic.no_hash().into_string()).collect();
+            info!(self.log, "Dry run: would publish message"; "topics" => format!("{:?}", topics), "size" => message.len());
+            return Ok(());
+        }
+        let mut result = Ok(());
         for topic in topics {
+            let topic_hash = topic.no_hash();
+            if self.lacks_publish_peers(&topic_hash) {
+                let held = self.hold_publish(topic, message.clone());
+                if result.is_ok() {
+                    result = Err(if held {
+                        PublishError::Held(topic_hash)
+                    } else {
+                        PublishError::HoldQueueFull(topic_hash)
+                    });
+                }
+                continue;
+            }
             self.gossipsub.publish(&topic, message.clone());
+            if result.is_ok() {
+                result = self.publish_reach(&topic_hash);
+            }
+        }
+        result
+    }
+
+    /// Whether fewer peers than `min_publish_peers` subscribe to a topic.
+    fn lacks_publish_peers(&self, topic: &TopicHash) -> bool {
+        let min = match self.min_publish_peers {
+            Some(min) => min,
+            None => return false,
+        };
+        let subscribers = self
+            .peer_topics
+            .values()
+            .filter(|topics| topics.contains(topic))
+            .count();
+        subscribers < min
+    }
+
+    /// Holds a message until enough peers subscribe to its topic. Returns false if too many
+    /// messages are already held, in which case the message is dropped.
+    fn hold_publish(&mut self, topic: Topic, message: Vec<u8>) -> bool {
+        self.expire_held_publishes();
+        if self.held_publishes.len() >= MAX_HELD_PUBLISHES {
+            warn!(self.log, "Too many messages held for subscribers, dropping message"; "topic" => topic.no_hash().as_str());
+            return false;
+        }
+        debug!(self.log, "Holding message until enough peers subscribe"; "topic" => topic.no_hash().as_str());
+        let expires = clock::now() + self.publish_hold_timeout;
+        self.held_publishes.push_back((topic, message, expires));
+        true
+    }
+
+    /// Drops held messages as they expire, keeping the expiry timer set for the oldest one.
+    fn poll_hold_expiry(&mut self) {
+        loop {
+            self.expire_held_publishes();
+            // held messages share a timeout, so the oldest expires first
+            let expires = match self.held_publishes.front() {
+                Some((_, _, expires)) => *expires,
+                None => {
+                    self.hold_expiry = None;
+                    return;
+                }
+            };
+            match self.hold_expiry.as_mut() {
+                Some(delay) if delay.deadline() != expires => delay.reset(expires),
+                Some(_) => {}
+                None => self.hold_expiry = Some(Delay::new(expires)),
+            }
+            match self.hold_expiry.as_mut().map(Future::poll) {
+                Some(Ok(Async::Ready(()))) if expires <= clock::now() => {}
+                Some(Err(e)) => {
+                    warn!(self.log, "Held message expiry timer failed"; "error" => format!("{:?}", e));
+                    self.hold_expiry = None;
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Drops held messages that have waited too long for subscribers.
+    fn expire_held_publishes(&mut self) {
+        let now = clock::now();
+        let before = self.held_publishes.len();
+        self.held_publishes.retain(|(_, _, expires)| *expires > now);
+        let expired = before - self.held_publishes.len();
+        if expired > 0 {
+            debug!(self.log, "Dropped held messages that found too few subscribers"; "messages" => expired);
+        }
+    }
+
+    /// Publishes the held messages for a topic, once enough peers subscribe to it.
+    fn release_held_publishes(&mut self, topic: &TopicHash) {
+        self.expire_held_publishes();
+        if self.lacks_publish_peers(topic) {
+            return;
+        }
+        let (ready, held): (VecDeque<_>, VecDeque<_>) = self
+            .held_publishes
+            .drain(..)
+            .partition(|(held_topic, _, _)| held_topic.no_hash() == *topic);
+        self.held_publishes = held;
+        for (held_topic, message, _) in ready {
+            self.gossipsub.publish(&held_topic, message);
+        }
+    }
+
+    /// Whether a message published to a topic can reach any peer.
+    fn publish_reach(&self, topic: &TopicHash) -> Result<(), PublishError> {
+        if self.discovery.connected_peer_set().is_empty() {
+            return Err(PublishError::NoPeers);
+        }
+        if !self.peer_topics.values().any(|topics| topics.contains(topic)) {
+            return Err(PublishError::NoSubscribers(topic.clone()));
+        }
+        if self.mesh_likely_empty(topic) {
+            return Err(PublishError::EmptyMesh(topic.clone()));
+        }
+        Ok(())
+    }
+
+    /// Whether the mesh for a topic we subscribe to is likely empty, because all of its
+    /// subscribers subscribed since the last gossipsub heartbeat could have added them. This is
+    /// an estimate, as gossipsub does not expose its mesh. Topics we do not subscribe to are
+    /// published to fanout peers, which are chosen from the subscribers at publish time.
+    fn mesh_likely_empty(&self, topic: &TopicHash) -> bool {
+        if !self.subscriptions.contains(topic) {
+            return false;
+        }
+        let now = clock::now();
+        self.peer_subscribed_at
+            .iter()
+            .filter(|((_, subscribed_topic), _)| subscribed_topic == topic)
+            .all(|(_, subscribed_at)| now.duration_since(*subscribed_at) < self.heartbeat_interval)
+    }
+
+    /// Sets whether the application is syncing. While syncing, received gossip is dropped unless
+    /// it is on one of the configured `sync_allowed_topics`. Gossipsub forwards messages before
+    /// they reach the behaviour, so dropped gossip is still propagated.
+    pub fn set_syncing(&mut self, syncing: bool) {
+        if self.syncing != syncing {
+            debug!(self.log, "Sync state changed"; "syncing" => syncing);
+        }
+        self.syncing = syncing;
+    }
+
+    /// The number of RPC substreams currently open, or waiting to be opened, across all
+    /// connections.
+    pub fn open_substreams(&self) -> usize {
+        self.serenity_rpc.open_substreams()
+    }
+
+    /// The number of received gossip messages dropped before reaching the application, by
+    /// reason, since the behaviour started.
+    pub fn dropped_message_stats(&self) -> HashMap<DropReason, u64> {
+        self.dropped_messages.clone()
+    }
+
+    /// The number of gossip messages currently out for application validation.
+    pub fn validations_in_flight(&self) -> usize {
+        self.validations_in_flight.len()
+    }
+
+    /// Whether received gossip messages are waiting for a validation slot. The service stops
+    /// reading from the network until they are handed out, so that peers are slowed by
+    /// backpressure rather than their messages being dropped.
+    pub fn validation_backlogged(&self) -> bool {
+        !self.pending_validations.is_empty()
+    }
+
+    /// Informs the behaviour that the application has finished validating the gossip message
+    /// with the given id, releasing its slots to the waiting messages that can now proceed.
+    /// Returns false, changing nothing, if the message is not out for validation, such as when
+    /// its id has already been completed.
+    pub fn validation_complete(&mut self, id: u64) -> bool {
+        let topics = match self.validations_in_flight.remove(&id) {
+            Some(topics) => topics,
+            None => return false,
+        };
+        for topic in &topics {
+            if let Some(in_flight) = self.topic_validations_in_flight.get_mut(topic) {
+                *in_flight = in_flight.saturating_sub(1);
+            }
         }
+        // waiting messages on other topics may proceed past those still blocked
+        let mut index = 0;
+        while index < self.pending_validations.len() {
+            if self.has_validation_slot(validation_topics(&self.pending_validations[index])) {
+                if let Some(event) = self.pending_validations.remove(index) {
+                    self.start_validation(event);
+                }
+            } else {
+                index += 1;
+            }
+        }
+        true
+    }
+
+    /// Sets which events the behaviour generates. Excluded events are dropped as they occur,
+    /// rather than being queued.
+    pub fn set_event_filter(&mut self, filter: EventFilter) {
+        self.event_filter = filter;
     }
 
     /* Eth2 RPC behaviour functions */
 
     /// Sends an RPC Request/Response via the RPC protocol.
     pub fn send_rpc(&mut self, peer_id: PeerId, rpc_event: RPCEvent) {
+        if self.dry_run {
+            info!(self.log, "Dry run: would send RPC"; "peer_id" => format!("{:?}", peer_id), "event" => format!("{:?}", rpc_event));
+            return;
+        }
         self.serenity_rpc.send_rpc(peer_id, rpc_event);
     }
 
+    /// Sets the item sent to peers that ask for our most recent item with a `SampleRecent`
+    /// request, such as our latest block.
+    pub fn set_recent_item(&mut self, item: Vec<u8>) {
+        self.recent_item = Some(item);
+    }
+
+    /// Answers a `SampleRecent` request with the application's most recent item.
+    fn respond_sample_recent(&mut self, peer_id: PeerId, id: RequestId) {
+        let response = match self.recent_item.clone() {
+            Some(item) => RPCErrorResponse::Success(RPCResponse::Message(item)),
+            None => RPCErrorResponse::ServerError(ErrorMessage {
+                error_message: b"No recent item".to_vec(),
+            }),
+        };
+        self.send_rpc(peer_id, RPCEvent::Response(id, response));
+    }
+
+    /// The RPC requests sent to peers that are still awaiting a response.
+    pub fn pending_rpc_requests(&self) -> Vec<(PeerId, RequestId)> {
+        self.serenity_rpc.pending_rpc_requests()
+    }
+
+    /// Abandons an RPC request awaiting a response. An `RPCError::Cancelled` error is reported
+    /// for it through the event stream. Returns whether the request was pending.
+    pub fn cancel_rpc(&mut self, peer_id: &PeerId, id: RequestId) -> bool {
+        self.serenity_rpc.cancel_rpc(peer_id, id)
+    }
+
+    /// Abandons every RPC request awaiting a response, reporting an `RPCError::Cancelled` error
+    /// for each.
+    pub fn cancel_all_rpc(&mut self) {
+        self.serenity_rpc.cancel_all_rpc();
+    }
+
     /* Discovery / Peer management functions */
     pub fn connected_peers(&self) -> &HashSet<PeerId> {
         self.discovery.connected_peer_set()
@@ -208,20 +1053,522 @@ impl<TSubstream: AsyncRead + AsyncWrite> Behaviour<TSubstream> {
     pub fn num_connected_peers(&self) -> usize {
         self.discovery.connected_peers()
     }
+
+    /// The number of peers in the discovery routing table, connected or not.
+    pub fn known_peer_count(&self) -> usize {
+        self.discovery.known_peer_count()
+    }
+
+    /// Replaces the strategy `sample_peers` uses to choose peers.
+    pub fn set_peer_selector(&mut self, selector: Box<dyn PeerSelector>) {
+        self.peer_selector = selector;
+    }
+
+    /// Chooses up to `count` connected peers using the peer selector, most preferred first.
+    pub fn sample_peers(&self, count: usize) -> Vec<PeerId> {
+        let mut candidates = self.discovery.peer_candidates();
+        for candidate in candidates.iter_mut() {
+            candidate.progress = self.peer_progress.get(&candidate.peer_id).cloned();
+        }
+        self.peer_selector.select(candidates, count)
+    }
+
+    /// The number of discovery routing table entries in each bucket, by log2 distance.
+    pub fn bucket_stats(&self) -> Vec<usize> {
+        self.discovery.bucket_stats()
+    }
+
+    /// Dials an address, such as a known bootnode's.
+    pub fn dial(&mut self, address: Multiaddr) {
+        if self.dry_run {
+            info!(self.log, "Dry run: would dial address"; "address" => format!("{}", address));
+            return;
+        }
+        self.discovery.dial(address);
+    }
+
+    /// Disconnects a connected peer. The peer may connect again later.
+    pub fn disconnect_peer(&mut self, peer_id: PeerId) {
+        if self.discovery.connected_peer_set().contains(&peer_id) {
+            self.discovery
+                .disconnect_peer(peer_id, DisconnectReason::Requested);
+        }
+    }
+
+    /// Bans a peer for the life of the node, disconnecting it if connected. Banned peers are not
+    /// dialed and their connections are closed as soon as they are established.
+    pub fn ban_peer(&mut self, peer_id: PeerId) {
+        self.discovery.ban_peer(peer_id);
+    }
+
+    /// Whether a peer has been banned.
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.discovery.is_banned(peer_id)
+    }
+
+    /// The peers that have been banned.
+    pub fn banned_peers(&self) -> &HashSet<PeerId> {
+        self.discovery.banned_peers()
+    }
+
+    /// Exempts a peer from eviction when we are over the peer limit.
+    pub fn pin_peer(&mut self, peer_id: PeerId) {
+        self.discovery.pin_peer(peer_id);
+    }
+
+    /// Makes a pinned peer eligible for eviction again.
+    pub fn unpin_peer(&mut self, peer_id: &PeerId) {
+        self.discovery.unpin_peer(peer_id);
+    }
+
+    /// Tags a connected peer with the purpose of its connection, so it is evicted after untagged
+    /// peers. The tag is cleared when the peer disconnects.
+    pub fn tag_connection(&mut self, peer_id: &PeerId, tag: ConnectionTag) {
+        self.discovery.tag_connection(peer_id, tag);
+    }
+
+    /// Removes the tag from a connected peer.
+    pub fn untag_connection(&mut self, peer_id: &PeerId) {
+        self.discovery.untag_connection(peer_id);
+    }
+
+    /// Reads a field from a known peer's ENR, such as a custom field set by the peer's
+    /// application.
+    pub fn peer_enr_field(&self, peer_id: &PeerId, key: &str) -> Option<Vec<u8>> {
+        self.discovery.peer_enr_field(peer_id, key)
+    }
+
+    /// Enters or leaves drain mode, in which no new connections are made or accepted.
+    pub fn set_draining(&mut self, draining: bool) {
+        self.discovery.set_draining(draining);
+    }
+
+    /// Whether the behaviour is draining connections.
+    pub fn is_draining(&self) -> bool {
+        self.discovery.is_draining()
+    }
+
+    /// Returns the connection lifecycle events recorded since the last call.
+    pub fn take_connection_events(&mut self) -> Vec<ConnectionLogEntry> {
+        self.discovery.take_connection_events()
+    }
+
+    /// Returns the peers that should be disconnected, emptying the queue.
+    pub fn take_peers_to_disconnect(&mut self) -> Vec<PeerId> {
+        self.discovery.take_peers_to_disconnect()
+    }
+
+    /// Queues peers that have sent us no RPC or gossip for `timeout` for disconnection. Peers
+    /// tagged for sync are kept. Returns when the next of the remaining peers would become idle.
+    pub fn disconnect_app_idle_peers(&mut self, timeout: Duration) -> Option<Instant> {
+        self.discovery.disconnect_app_idle_peers(timeout)
+    }
+
+    /// Whether any peers are waiting to be disconnected.
+    pub fn has_peers_to_disconnect(&self) -> bool {
+        self.discovery.has_peers_to_disconnect()
+    }
+
+    /// Informs the behaviour that the swarm has closed a peer's connection on our request. The
+    /// swarm does not report these closures itself.
+    pub fn peer_disconnected(&mut self, peer_id: PeerId) {
+        self.forget_peer(&peer_id);
+        if self.discovery.peer_disconnected(&peer_id) {
+            self.add_event(BehaviourEvent::PeerDisconnected(peer_id));
+        }
+    }
+
+    /// Asks for the address a connected peer sees us at. The peer reports it at its next identify
+    /// exchange with us, at which point the IP in our ENR is updated to match and the returned
+    /// receiver completes. The receiver is cancelled if the peer disconnects first.
+    pub fn request_observed_addr(&mut self, peer_id: PeerId) -> oneshot::Receiver<Multiaddr> {
+        let (sender, receiver) = oneshot::channel();
+        if self.discovery.connected_peer_set().contains(&peer_id) {
+            self.observed_addr_requests
+                .entry(peer_id)
+                .or_insert_with(Vec::new)
+                .push(sender);
+        }
+        receiver
+    }
+
+    /// Records an application-level progress marker for a connected peer, such as the highest
+    /// slot it has gossiped, replacing any earlier marker. Markers are dropped when the peer
+    /// disconnects, and are ignored for peers that are not connected.
+    pub fn note_peer_progress(&mut self, peer_id: PeerId, slot: u64) {
+        if self.discovery.connected_peer_set().contains(&peer_id) {
+            self.peer_progress.insert(peer_id, slot);
+        }
+    }
+
+    /// The progress marker last recorded for a peer.
+    pub fn peer_progress(&self, peer_id: &PeerId) -> Option<u64> {
+        self.peer_progress.get(peer_id).cloned()
+    }
+
+    /// The multiplexer negotiated for the connection to a peer. Its settings are those of the
+    /// configured `TransportBuffers`.
+    pub fn peer_multiplexer(&self, peer_id: &PeerId) -> Option<MultiplexerKind> {
+        self.multiplexers.get(peer_id)
+    }
+
+    /// A page of the protocols a peer supports, as reported through identify, in sorted order.
+    /// Empty if the peer has not been identified or the page is past the end of the list.
+    pub fn peer_protocols_page(&self, peer_id: &PeerId, offset: usize, limit: usize) -> Vec<String> {
+        self.peer_protocols
+            .get(peer_id)
+            .map(|protocols| {
+                protocols
+                    .iter()
+                    .skip(offset)
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Pauses or resumes discovery queries, such as while the application is behind on
+    /// messages.
+    pub fn set_discovery_paused(&mut self, paused: bool) {
+        self.discovery.set_queries_paused(paused);
+    }
+
+    /// Whether discovery queries are paused.
+    pub fn discovery_paused(&self) -> bool {
+        self.discovery.queries_paused()
+    }
+
+    /// Closes the discovery socket, freeing the discovery port.
+    pub fn close_discovery(&mut self) {
+        self.discovery.close();
+    }
+
+    /// Restarts closed discovery on the discovery port.
+    pub fn reopen_discovery(&mut self, local_key: &Keypair, config: &NetworkConfig) -> error::Result<()> {
+        self.discovery.reopen(local_key, config)
+    }
+
+    /// Takes over the application-set state of the behaviour being replaced when the identity
+    /// is rotated: subscriptions and advertised subnets, bans and pins, the event filter, sync
+    /// state, the recent item, draining, the peer selector and held publishes.
+    ///
+    /// Connection tags and messages out for validation belong to connections that are dropped
+    /// with the old identity, so they are not carried over. Completing the old messages' ids is
+    /// ignored.
+    pub fn inherit(&mut self, old: &mut Behaviour<TSubstream>) {
+        for topic in old.subscriptions.iter() {
+            self.subscribe(Topic::new(topic.clone().into_string()));
+        }
+        let attnets = old.discovery.attnets();
+        for subnet_id in (0..ATTESTATION_SUBNET_COUNT).filter(|subnet| attnets & (1 << *subnet) != 0) {
+            if let Err(e) = self.discovery.update_attnets(subnet_id, true) {
+                warn!(self.log, "Could not advertise attestation subnet"; "subnet_id" => subnet_id, "error" => e);
+            }
+        }
+        for peer_id in old.discovery.banned_peers().iter() {
+            self.discovery.ban_peer(peer_id.clone());
+        }
+        for peer_id in old.discovery.pinned_peers().iter() {
+            self.discovery.pin_peer(peer_id.clone());
+        }
+        self.discovery.set_draining(old.discovery.is_draining());
+        self.event_filter = old.event_filter;
+        self.syncing = old.syncing;
+        self.recent_item = old.recent_item.take();
+        self.peer_selector = std::mem::replace(&mut old.peer_selector, Box::new(MostRecentlyActive));
+        self.held_publishes = std::mem::replace(&mut old.held_publishes, VecDeque::new());
+
+        // the old identity's connections close with its swarm, so report them from this one
+        for peer_id in old.discovery.drop_connections(DisconnectReason::IdentityRotated) {
+            self.add_event(BehaviourEvent::PeerDisconnected(peer_id));
+        }
+    }
+}
+
+/// Why a published message could reach no peers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublishError {
+    /// We have no connected peers.
+    NoPeers,
+    /// None of our connected peers subscribe to the topic.
+    NoSubscribers(TopicHash),
+    /// Too few peers subscribe to the topic, so the message is held until `min_publish_peers`
+    /// do or it expires.
+    Held(TopicHash),
+    /// Too few peers subscribe to the topic, and too many messages are already held, so the
+    /// message was dropped.
+    HoldQueueFull(TopicHash),
+    /// Peers subscribe to the topic, but likely none are in our mesh for it yet. Gossipsub does
+    /// not expose its mesh, so this is estimated: every subscriber subscribed within the last
+    /// heartbeat interval while we subscribe to the topic.
+    EmptyMesh(TopicHash),
+}
+
+impl std::fmt::Display for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PublishError::NoPeers => write!(f, "No connected peers"),
+            PublishError::NoSubscribers(topic) => {
+                write!(f, "No connected peers subscribe to {}", topic.as_str())
+            }
+            PublishError::Held(topic) => {
+                write!(f, "Held until enough peers subscribe to {}", topic.as_str())
+            }
+            PublishError::HoldQueueFull(topic) => write!(
+                f,
+                "Too many messages held for subscribers, dropped message for {}",
+                topic.as_str()
+            ),
+            PublishError::EmptyMesh(topic) => {
+                write!(f, "No peers are in the mesh for {} yet", topic.as_str())
+            }
+        }
+    }
+}
+
+/// Why a received gossip message was dropped before reaching the application.
+///
+/// Gossipsub discards duplicates and our own messages before the behaviour sees them, so those
+/// are not counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// A probe whose payload could not be read.
+    MalformedProbe,
+    /// The event filter excludes gossip messages, or probes for a probe.
+    Filtered,
+    /// The application is syncing and the message is not on a sync-allowed topic.
+    Syncing,
+    /// The message exceeds the maximum size of one of its topics.
+    Oversized,
+    /// Too many messages were already waiting for a validation slot.
+    ValidationQueueFull,
+    /// A message on a chunked topic that is not a chunk.
+    MalformedChunk,
+    /// Too many messages were already waiting for room in the channel to the application.
+    DeliveryBacklogFull,
+}
+
+/// A stream multiplexer that can be negotiated for a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MultiplexerKind {
+    Yamux,
+    Mplex,
+}
+
+/// The multiplexer negotiated with each peer. Shared between the transport, which negotiates it,
+/// and the behaviour, which reports it.
+#[derive(Clone, Default)]
+pub struct NegotiatedMultiplexers {
+    inner: Arc<Mutex<HashMap<PeerId, MultiplexerKind>>>,
+}
+
+impl NegotiatedMultiplexers {
+    /// Records the multiplexer negotiated for a new connection to a peer.
+    pub fn record(&self, peer_id: PeerId, kind: MultiplexerKind) {
+        self.inner.lock().insert(peer_id, kind);
+    }
+
+    /// The multiplexer negotiated with a peer, if it is connected.
+    fn get(&self, peer_id: &PeerId) -> Option<MultiplexerKind> {
+        self.inner.lock().get(peer_id).cloned()
+    }
+
+    /// Drops the record of a peer that has disconnected.
+    fn forget(&self, peer_id: &PeerId) {
+        self.inner.lock().remove(peer_id);
+    }
+}
+
+/// Gossip subscriptions exported from one behaviour to be imported into another.
+///
+/// Gossipsub does not expose its mesh, so mesh membership is not carried over.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct GossipState {
+    /// The topics subscribed to.
+    pub topics: BTreeSet<String>,
+}
+
+/// The gossipsub topic carrying diagnostic probes.
+fn probe_topic() -> Topic {
+    Topic::new(format!(
+        "/{}/{}/{}",
+        TOPIC_PREFIX, PROBE_TOPIC, TOPIC_ENCODING_POSTFIX
+    ))
+}
+
+/// The time since a probe was published, from the millisecond timestamp it carries. Clock
+/// differences between nodes can make the probe appear to arrive before it was sent, which is
+/// reported as zero latency.
+fn probe_latency(probe: &[u8]) -> Option<Duration> {
+    if probe.len() != 8 {
+        return None;
+    }
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(probe);
+    let sent = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(timestamp));
+    Some(SystemTime::now().duration_since(sent).unwrap_or_default())
+}
+
+/// The topics whose validation slots a message waiting for validation takes.
+fn validation_topics(event: &BehaviourEvent) -> &[TopicHash] {
+    match event {
+        BehaviourEvent::PubsubMessage { topics, .. } => topics,
+        BehaviourEvent::ChunkedMessageComplete { topic, .. } => std::slice::from_ref(topic),
+        _ => &[],
+    }
+}
+
+/// The id a message waiting for validation is completed with.
+fn validation_id(event: &BehaviourEvent) -> Option<u64> {
+    match event {
+        BehaviourEvent::PubsubMessage { id, .. }
+        | BehaviourEvent::ChunkedMessageComplete { id, .. } => Some(*id),
+        _ => None,
+    }
+}
+
+/// The topic names, between TOPIC_PREFIX and TOPIC_ENCODING_POSTFIX, of the topics we know.
+const KNOWN_TOPICS: &[&str] = &[
+    BEACON_BLOCK_TOPIC,
+    BEACON_ATTESTATION_TOPIC,
+    VOLUNTARY_EXIT_TOPIC,
+    PROPOSER_SLASHING_TOPIC,
+    ATTESTER_SLASHING_TOPIC,
+    PROBE_TOPIC,
+];
+
+/// What a known topic carries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TopicKind {
+    Block,
+    /// The beacon attestation topic, an attestation subnet topic or a shard topic.
+    Attestation,
+    /// Any other of the known topics.
+    Other,
+}
+
+/// Parses a topic of the form /TOPIC_PREFIX/name/TOPIC_ENCODING_POSTFIX, returning its kind if
+/// it is one of the known topics, an attestation subnet topic or a shard topic.
+fn topic_kind(topic: &TopicHash) -> Option<TopicKind> {
+    let topic_parts: Vec<&str> = topic.as_str().split('/').collect();
+    if topic_parts.len() != 4
+        || !topic_parts[0].is_empty()
+        || topic_parts[1] != TOPIC_PREFIX
+        || topic_parts[3] != TOPIC_ENCODING_POSTFIX
+    {
+        return None;
+    }
+    let name = topic_parts[2];
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let subnet_suffix = format!("_{}", BEACON_ATTESTATION_TOPIC);
+    let is_shard =
+        name.starts_with(SHARD_TOPIC_PREFIX) && is_number(&name[SHARD_TOPIC_PREFIX.len()..]);
+    let is_subnet = name.starts_with(COMMITTEE_INDEX_TOPIC_PREFIX)
+        && name.ends_with(&subnet_suffix)
+        && name.len() >= COMMITTEE_INDEX_TOPIC_PREFIX.len() + subnet_suffix.len()
+        && is_number(&name[COMMITTEE_INDEX_TOPIC_PREFIX.len()..name.len() - subnet_suffix.len()]);
+    if name == BEACON_BLOCK_TOPIC {
+        Some(TopicKind::Block)
+    } else if name == BEACON_ATTESTATION_TOPIC || is_shard || is_subnet {
+        Some(TopicKind::Attestation)
+    } else if KNOWN_TOPICS.contains(&name) {
+        Some(TopicKind::Other)
+    } else {
+        None
+    }
+}
+
+/// Whether a topic is one of the known topics, an attestation subnet topic or a shard topic.
+fn is_known_topic(topic: &TopicHash) -> bool {
+    topic_kind(topic).is_some()
+}
+
+/// The gossipsub topic for an attestation subnet.
+fn attnet_topic(subnet_id: u64) -> Topic {
+    Topic::new(format!(
+        "/{}/{}{}_{}/{}",
+        TOPIC_PREFIX,
+        COMMITTEE_INDEX_TOPIC_PREFIX,
+        subnet_id,
+        BEACON_ATTESTATION_TOPIC,
+        TOPIC_ENCODING_POSTFIX
+    ))
 }
 
 /// The types of events than can be obtained from polling the behaviour.
 pub enum BehaviourEvent {
     RPC(PeerId, RPCEvent),
+    /// A peer's RPC request was refused because the open substream limit was reached.
+    InboundRefused { peer_id: PeerId, request: RPCRequest },
     PeerDialed(PeerId),
     PeerDisconnected(PeerId),
     PubsubMessage {
+        /// Identifies the message to `validation_complete`.
+        id: u64,
         source: PeerId,
         topics: Vec<TopicHash>,
-        message: Vec<u8>,
+        message: PubsubMessage,
+    },
+    /// A dialed address was answered by a peer other than the one we expected there. The
+    /// connection was rejected.
+    PeerIdMismatch { claimed: PeerId, derived: PeerId },
+    /// A diagnostic probe arrived, `latency` after it was published.
+    ProbeReceived { origin: PeerId, latency: Duration },
+    /// All chunks of a message published with `publish_chunked` have arrived.
+    ChunkedMessageComplete {
+        /// Identifies the message to `validation_complete`.
+        id: u64,
+        source: PeerId,
+        topic: TopicHash,
+        data: Vec<u8>,
     },
 }
 
+/// Selects which `BehaviourEvent`s, and so which `Libp2pEvent`s, are generated. All events are
+/// generated by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventFilter {
+    /// Generate RPC requests and responses, and reports of refused requests.
+    pub rpc: bool,
+    /// Generate events for peers we have dialed.
+    pub peer_dialed: bool,
+    /// Generate events for peers that have disconnected.
+    pub peer_disconnected: bool,
+    /// Generate gossip messages.
+    pub pubsub_message: bool,
+    /// Generate events for diagnostic probes received.
+    pub probe_received: bool,
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        EventFilter {
+            rpc: true,
+            peer_dialed: true,
+            peer_disconnected: true,
+            pubsub_message: true,
+            probe_received: true,
+        }
+    }
+}
+
+impl EventFilter {
+    /// Whether the filter lets an event through.
+    pub fn allows(&self, event: &BehaviourEvent) -> bool {
+        match event {
+            BehaviourEvent::RPC(..) | BehaviourEvent::InboundRefused { .. } => self.rpc,
+            BehaviourEvent::PeerDialed(_) => self.peer_dialed,
+            BehaviourEvent::PeerDisconnected(_) => self.peer_disconnected,
+            BehaviourEvent::PubsubMessage { .. }
+            | BehaviourEvent::ChunkedMessageComplete { .. } => self.pubsub_message,
+            BehaviourEvent::ProbeReceived { .. } => self.probe_received,
+            // possible attacks are always reported
+            BehaviourEvent::PeerIdMismatch { .. } => true,
+        }
+    }
+}
+
 /// Messages that are passed to and from the pubsub (Gossipsub) behaviour.
 #[derive(Debug, Clone, PartialEq)]
 pub enum PubsubMessage {
@@ -231,4 +1578,545 @@ pub enum PubsubMessage {
     Attestation(Vec<u8>),
     /// Gossipsub message from an unknown topic.
     Unknown(Vec<u8>),
-}
\ No newline at end of file
+}
+
+impl PubsubMessage {
+    /// Classifies a gossip message by the first of its topics that is recognised. Attestations
+    /// may arrive on the beacon attestation topic, a shard topic or a committee subnet topic.
+    pub fn from_topics(topics: &[TopicHash], data: Vec<u8>) -> Self {
+        for topic in topics {
+            match topic_kind(topic) {
+                Some(TopicKind::Block) => return PubsubMessage::Block(data),
+                Some(TopicKind::Attestation) => return PubsubMessage::Attestation(data),
+                Some(TopicKind::Other) | None => {}
+            }
+        }
+        PubsubMessage::Unknown(data)
+    }
+
+    /// The message's payload.
+    pub fn data(&self) -> &[u8] {
+        match self {
+            PubsubMessage::Block(data)
+            | PubsubMessage::Attestation(data)
+            | PubsubMessage::Unknown(data) => data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::test_utils::*;
+
+    fn topic(name: &str) -> TopicHash {
+        Topic::new(format!("/{}/{}/{}", TOPIC_PREFIX, name, TOPIC_ENCODING_POSTFIX)).no_hash()
+    }
+
+    fn receive(behaviour: &mut Behaviour<TestSubstream>, name: &str) {
+        behaviour.handle_gossip(PeerId::random(), vec![topic(name)], vec![1, 2, 3]);
+    }
+
+    /// Takes the gossip messages handed to the application, returning their ids.
+    fn handed_out(behaviour: &mut Behaviour<TestSubstream>) -> Vec<u64> {
+        let mut ids = Vec::new();
+        while let Some(event) = behaviour.take_event() {
+            if let BehaviourEvent::PubsubMessage { id, .. } = event {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn validations_are_limited_and_released_by_id() {
+        let mut config = test_config();
+        config.max_concurrent_validations = Some(2);
+        let mut behaviour = test_behaviour(&config);
+        for _ in 0..5 {
+            receive(&mut behaviour, BEACON_BLOCK_TOPIC);
+        }
+        let mut received = handed_out(&mut behaviour);
+        assert_eq!(received.len(), 2);
+        assert!(behaviour.validation_backlogged());
+
+        // each completion lets exactly one waiting message through
+        let mut completed = 0;
+        while completed < received.len() {
+            assert!(behaviour.validation_complete(received[completed]));
+            completed += 1;
+            received.extend(handed_out(&mut behaviour));
+            assert!(behaviour.validations_in_flight() <= 2);
+        }
+        assert_eq!(received.len(), 5);
+        assert_eq!(behaviour.validations_in_flight(), 0);
+        assert!(!behaviour.validation_backlogged());
+    }
+
+    #[test]
+    fn unknown_and_repeated_validation_ids_are_ignored() {
+        let mut config = test_config();
+        config.max_concurrent_validations = Some(1);
+        let mut behaviour = test_behaviour(&config);
+        receive(&mut behaviour, BEACON_BLOCK_TOPIC);
+        receive(&mut behaviour, BEACON_BLOCK_TOPIC);
+        let first = handed_out(&mut behaviour);
+        assert_eq!(first.len(), 1);
+
+        // an id that was never handed out frees no slot
+        assert!(!behaviour.validation_complete(first[0] + 100));
+        assert!(handed_out(&mut behaviour).is_empty());
+
+        assert!(behaviour.validation_complete(first[0]));
+        let second = handed_out(&mut behaviour);
+        assert_eq!(second.len(), 1);
+        // completing the first message again must not hand out more than the limit
+        assert!(!behaviour.validation_complete(first[0]));
+        assert_eq!(behaviour.validations_in_flight(), 1);
+    }
+
+    #[test]
+    fn gossipsub_protocol_id_is_fixed() {
+        let mut config = test_config();
+        config.gs_config = crate::GossipsubConfigBuilder::new()
+            .max_transmit_size(1024)
+            .build();
+        let behaviour = test_behaviour(&config);
+        assert_eq!(behaviour.gossipsub_protocol_ids(), vec!["/meshsub/1.0.0".to_string()]);
+    }
+
+    /// Takes the chunked messages handed to the application, returning their data.
+    fn reassembled(behaviour: &mut Behaviour<TestSubstream>) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        while let Some(event) = behaviour.take_event() {
+            if let BehaviourEvent::ChunkedMessageComplete { data, .. } = event {
+                messages.push(data);
+            }
+        }
+        messages
+    }
+
+    fn chunked_config() -> NetworkConfig {
+        let mut config = test_config();
+        config.chunked_topics = vec![topic("large").into_string()];
+        config
+    }
+
+    #[test]
+    fn chunks_on_a_chunked_topic_are_reassembled_for_validation() {
+        let mut config = chunked_config();
+        config.max_concurrent_validations = Some(1);
+        let mut behaviour = test_behaviour(&config);
+        let data: Vec<u8> = (0..30).collect();
+        let chunks = chunking::split(5, &data, 10).unwrap();
+        assert_eq!(chunks.len(), 3);
+
+        let source = PeerId::random();
+        for chunk in chunks {
+            behaviour.handle_gossip(source.clone(), vec![topic("large")], chunk);
+        }
+        assert_eq!(reassembled(&mut behaviour), vec![data]);
+        assert_eq!(behaviour.validations_in_flight(), 1);
+    }
+
+    #[test]
+    fn chunk_magic_on_other_topics_is_ordinary_gossip() {
+        let mut behaviour = test_behaviour(&chunked_config());
+        let message = chunking::split(5, b"not a chunk", 64).unwrap().remove(0);
+        behaviour.handle_gossip(PeerId::random(), vec![topic(BEACON_BLOCK_TOPIC)], message.clone());
+
+        match behaviour.take_event() {
+            Some(BehaviourEvent::PubsubMessage { message: received, .. }) => {
+                assert_eq!(received.data(), &message[..])
+            }
+            _ => panic!("expected the message to be delivered as is"),
+        }
+    }
+
+    #[test]
+    fn messages_on_a_chunked_topic_must_be_chunks() {
+        let mut behaviour = test_behaviour(&chunked_config());
+        behaviour.handle_gossip(PeerId::random(), vec![topic("large")], vec![1, 2, 3]);
+        assert!(behaviour.take_event().is_none());
+        assert_eq!(
+            behaviour.dropped_message_stats().get(&DropReason::MalformedChunk),
+            Some(&1)
+        );
+        assert!(behaviour
+            .publish_chunked(Topic::new(topic(BEACON_BLOCK_TOPIC).into_string()), &[0; 10], 5)
+            .is_err());
+    }
+
+    #[test]
+    fn block_topics_are_classified_as_blocks() {
+        let message = PubsubMessage::from_topics(&[topic(BEACON_BLOCK_TOPIC)], vec![1]);
+        assert_eq!(message, PubsubMessage::Block(vec![1]));
+    }
+
+    #[test]
+    fn attestation_topics_are_classified_as_attestations() {
+        for name in &[
+            BEACON_ATTESTATION_TOPIC.to_string(),
+            format!("{}7", SHARD_TOPIC_PREFIX),
+            format!("{}3_{}", COMMITTEE_INDEX_TOPIC_PREFIX, BEACON_ATTESTATION_TOPIC),
+        ] {
+            let message = PubsubMessage::from_topics(&[topic(name)], vec![1]);
+            assert_eq!(message, PubsubMessage::Attestation(vec![1]), "topic {}", name);
+        }
+    }
+
+    #[test]
+    fn the_first_recognised_of_several_topics_classifies_a_message() {
+        let topics = vec![
+            Topic::new("/other/topic".to_string()).no_hash(),
+            topic(BEACON_BLOCK_TOPIC),
+            topic(BEACON_ATTESTATION_TOPIC),
+        ];
+        assert_eq!(
+            PubsubMessage::from_topics(&topics, vec![1]),
+            PubsubMessage::Block(vec![1])
+        );
+    }
+
+    #[test]
+    fn unrecognised_topics_are_classified_as_unknown() {
+        let topics = vec![
+            topic(VOLUNTARY_EXIT_TOPIC),
+            Topic::new(format!("/{}/{}/json", TOPIC_PREFIX, BEACON_BLOCK_TOPIC)).no_hash(),
+            Topic::new(format!("/{}/{}", TOPIC_PREFIX, BEACON_BLOCK_TOPIC)).no_hash(),
+        ];
+        assert_eq!(
+            PubsubMessage::from_topics(&topics, vec![1]),
+            PubsubMessage::Unknown(vec![1])
+        );
+    }
+
+    fn subscribe_peer(behaviour: &mut Behaviour<TestSubstream>, peer_id: &PeerId, topic: TopicHash) {
+        NetworkBehaviourEventProcess::<GossipsubEvent>::inject_event(
+            behaviour,
+            GossipsubEvent::Subscribed {
+                peer_id: peer_id.clone(),
+                topic,
+            },
+        );
+    }
+
+    #[test]
+    fn known_topics_are_supported() {
+        let attnet = format!("{}12_{}", COMMITTEE_INDEX_TOPIC_PREFIX, BEACON_ATTESTATION_TOPIC);
+        let shard = format!("{}7", SHARD_TOPIC_PREFIX);
+        for name in KNOWN_TOPICS.iter().cloned().chain(vec![&attnet[..], &shard[..]]) {
+            assert!(is_known_topic(&topic(name)), "{} should be known", name);
+        }
+        let unknown_subnet = format!("{}_{}", COMMITTEE_INDEX_TOPIC_PREFIX, BEACON_ATTESTATION_TOPIC);
+        let shard_suffix = format!("{}_blocks", SHARD_TOPIC_PREFIX);
+        for name in &["other_network_topic", &unknown_subnet[..], &shard_suffix[..]] {
+            assert!(!is_known_topic(&topic(name)), "{} should be unknown", name);
+        }
+        assert!(!is_known_topic(&Topic::new("/mothra/test".into()).no_hash()));
+    }
+
+    #[test]
+    fn strict_policy_disconnects_peers_announcing_unknown_topics() {
+        let mut config = test_config();
+        config.strict_topic_policy = true;
+        let mut behaviour = test_behaviour(&config);
+        let known = PeerId::random();
+        let unknown = PeerId::random();
+        subscribe_peer(&mut behaviour, &known, topic(VOLUNTARY_EXIT_TOPIC));
+        subscribe_peer(&mut behaviour, &unknown, topic("other_network_topic"));
+
+        assert_eq!(behaviour.take_peers_to_disconnect(), vec![unknown.clone()]);
+        assert!(behaviour.peer_topics(&known).is_some());
+        assert!(behaviour.peer_topics(&unknown).is_none());
+    }
+
+    #[test]
+    fn default_policy_tracks_peers_announcing_unknown_topics() {
+        let mut behaviour = test_behaviour(&test_config());
+        let peer_id = PeerId::random();
+        subscribe_peer(&mut behaviour, &peer_id, topic("other_network_topic"));
+
+        assert!(behaviour.take_peers_to_disconnect().is_empty());
+        assert!(behaviour
+            .peer_topics(&peer_id)
+            .unwrap()
+            .contains(&topic("other_network_topic")));
+    }
+
+    fn connect_peer(behaviour: &mut Behaviour<TestSubstream>) -> PeerId {
+        let peer_id = PeerId::random();
+        let port = 9000 + behaviour.discovery.connected_peers();
+        libp2p::swarm::NetworkBehaviour::inject_connected(
+            &mut behaviour.discovery,
+            peer_id.clone(),
+            libp2p::core::ConnectedPoint::Dialer {
+                address: format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap(),
+            },
+        );
+        peer_id
+    }
+
+    fn publish_block(behaviour: &mut Behaviour<TestSubstream>) -> Result<(), PublishError> {
+        behaviour.publish(
+            vec![Topic::new(topic(BEACON_BLOCK_TOPIC).into_string())],
+            vec![1, 2, 3],
+        )
+    }
+
+    #[test]
+    fn publishing_without_peers_reports_no_peers() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut behaviour = test_behaviour(&test_config());
+            assert_eq!(publish_block(&mut behaviour), Err(PublishError::NoPeers));
+        });
+    }
+
+    #[test]
+    fn publishing_without_subscribers_reports_no_subscribers() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut behaviour = test_behaviour(&test_config());
+            let peer_id = connect_peer(&mut behaviour);
+            subscribe_peer(&mut behaviour, &peer_id, topic(BEACON_ATTESTATION_TOPIC));
+            assert_eq!(
+                publish_block(&mut behaviour),
+                Err(PublishError::NoSubscribers(topic(BEACON_BLOCK_TOPIC)))
+            );
+        });
+    }
+
+    #[test]
+    fn publishing_before_subscribers_join_the_mesh_reports_an_empty_mesh() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let config = test_config();
+            let mut behaviour = test_behaviour(&config);
+            behaviour.subscribe(Topic::new(topic(BEACON_BLOCK_TOPIC).into_string()));
+            let peer_id = connect_peer(&mut behaviour);
+            subscribe_peer(&mut behaviour, &peer_id, topic(BEACON_BLOCK_TOPIC));
+            assert_eq!(
+                publish_block(&mut behaviour),
+                Err(PublishError::EmptyMesh(topic(BEACON_BLOCK_TOPIC)))
+            );
+
+            // a heartbeat has since had the chance to graft the subscriber
+            clock.advance(config.gs_config.heartbeat_interval);
+            assert_eq!(publish_block(&mut behaviour), Ok(()));
+        });
+    }
+
+    #[test]
+    fn chunked_publishes_report_the_reach_of_the_message() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut behaviour = test_behaviour(&chunked_config());
+            let large = Topic::new(topic("large").into_string());
+            assert_eq!(
+                behaviour.publish_chunked(large, &[0; 10], 5),
+                Err(PublishError::NoPeers.to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn held_publishes_are_released_by_subscribers_or_expire() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut config = test_config();
+            config.min_publish_peers = Some(1);
+            let mut behaviour = test_behaviour(&config);
+            let peer_id = connect_peer(&mut behaviour);
+            assert_eq!(
+                publish_block(&mut behaviour),
+                Err(PublishError::Held(topic(BEACON_BLOCK_TOPIC)))
+            );
+            assert_eq!(behaviour.held_publishes.len(), 1);
+
+            // a subscriber releases the held message
+            subscribe_peer(&mut behaviour, &peer_id, topic(BEACON_BLOCK_TOPIC));
+            assert!(behaviour.held_publishes.is_empty());
+
+            // messages for a topic without enough subscribers expire once the timeout passes
+            let attestation = Topic::new(topic(BEACON_ATTESTATION_TOPIC).into_string());
+            assert_eq!(
+                behaviour.publish(vec![attestation], vec![1]),
+                Err(PublishError::Held(topic(BEACON_ATTESTATION_TOPIC)))
+            );
+            let _ = behaviour.poll::<()>();
+            assert!(behaviour.hold_expiry.is_some());
+            clock.advance(config.publish_hold_timeout);
+            let _ = behaviour.poll::<()>();
+            assert!(behaviour.held_publishes.is_empty());
+            assert!(behaviour.hold_expiry.is_none());
+        });
+    }
+
+    /// Takes the gossip messages handed to the application, returning their ids split into
+    /// blocks and others.
+    fn handed_out_blocks(behaviour: &mut Behaviour<TestSubstream>) -> (Vec<u64>, Vec<u64>) {
+        let mut blocks = Vec::new();
+        let mut others = Vec::new();
+        while let Some(event) = behaviour.take_event() {
+            if let BehaviourEvent::PubsubMessage { id, message, .. } = event {
+                match message {
+                    PubsubMessage::Block(_) => blocks.push(id),
+                    _ => others.push(id),
+                }
+            }
+        }
+        (blocks, others)
+    }
+
+    #[test]
+    fn attestations_are_validated_while_a_block_is_in_flight() {
+        let mut config = test_config();
+        config.topic_validation_concurrency = vec![
+            (topic(BEACON_BLOCK_TOPIC).into_string(), 1),
+            (topic(BEACON_ATTESTATION_TOPIC).into_string(), 8),
+        ]
+        .into_iter()
+        .collect();
+        let mut behaviour = test_behaviour(&config);
+        receive(&mut behaviour, BEACON_BLOCK_TOPIC);
+        receive(&mut behaviour, BEACON_BLOCK_TOPIC);
+        for _ in 0..10 {
+            receive(&mut behaviour, BEACON_ATTESTATION_TOPIC);
+        }
+        let (blocks, attestations) = handed_out_blocks(&mut behaviour);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(attestations.len(), 8);
+
+        // completing attestations lets the waiting ones through, still behind the block
+        for id in &attestations[..2] {
+            assert!(behaviour.validation_complete(*id));
+        }
+        let (more_blocks, more_attestations) = handed_out_blocks(&mut behaviour);
+        assert!(more_blocks.is_empty());
+        assert_eq!(more_attestations.len(), 2);
+
+        // completing the block hands out the next one
+        assert!(behaviour.validation_complete(blocks[0]));
+        let (next_blocks, next_attestations) = handed_out_blocks(&mut behaviour);
+        assert_eq!(next_blocks.len(), 1);
+        assert_ne!(next_blocks[0], blocks[0]);
+        assert!(next_attestations.is_empty());
+        assert!(!behaviour.validation_backlogged());
+    }
+
+    fn receive_rpc(behaviour: &mut Behaviour<TestSubstream>, peer_id: &PeerId, event: RPCEvent) {
+        NetworkBehaviourEventProcess::<RPCMessage>::inject_event(
+            behaviour,
+            RPCMessage::RPC(peer_id.clone(), event),
+        );
+    }
+
+    #[test]
+    fn only_inbound_rpc_requests_and_responses_count_as_activity() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut behaviour = test_behaviour(&test_config());
+            let quiet = connect_peer(&mut behaviour);
+            let requesting = connect_peer(&mut behaviour);
+            let timeout = Duration::from_secs(60);
+            clock.advance(timeout);
+
+            // failures and what we send do not show the peer is useful
+            receive_rpc(
+                &mut behaviour,
+                &quiet,
+                RPCEvent::Error(1, crate::rpc::RPCError::Custom("Stream timeout".into())),
+            );
+            behaviour.send_rpc(
+                quiet.clone(),
+                RPCEvent::Request(2, RPCRequest::Message(vec![1])),
+            );
+            receive_rpc(
+                &mut behaviour,
+                &requesting,
+                RPCEvent::Request(3, RPCRequest::Message(vec![1])),
+            );
+
+            behaviour.disconnect_app_idle_peers(timeout);
+            assert_eq!(behaviour.take_peers_to_disconnect(), vec![quiet]);
+        });
+    }
+
+    fn receive_probe(behaviour: &mut Behaviour<TestSubstream>) {
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        behaviour.handle_gossip(
+            PeerId::random(),
+            vec![probe_topic().no_hash()],
+            sent_at.to_be_bytes().to_vec(),
+        );
+    }
+
+    #[test]
+    fn probes_are_subject_to_the_event_filter_and_sync_state() {
+        let mut behaviour = test_behaviour(&test_config());
+        receive_probe(&mut behaviour);
+        match behaviour.take_event() {
+            Some(BehaviourEvent::ProbeReceived { .. }) => {}
+            _ => panic!("expected the probe to be reported"),
+        }
+
+        // excluding gossip messages leaves probes alone
+        behaviour.set_event_filter(EventFilter {
+            pubsub_message: false,
+            ..EventFilter::default()
+        });
+        receive_probe(&mut behaviour);
+        assert!(behaviour.take_event().is_some());
+
+        behaviour.set_event_filter(EventFilter {
+            probe_received: false,
+            ..EventFilter::default()
+        });
+        receive_probe(&mut behaviour);
+        assert!(behaviour.take_event().is_none());
+        assert_eq!(
+            behaviour.dropped_message_stats().get(&DropReason::Filtered),
+            Some(&1)
+        );
+
+        behaviour.set_event_filter(EventFilter::default());
+        behaviour.set_syncing(true);
+        receive_probe(&mut behaviour);
+        assert!(behaviour.take_event().is_none());
+        assert_eq!(
+            behaviour.dropped_message_stats().get(&DropReason::Syncing),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn malformed_attestation_topics_are_not_classified() {
+        let topics = vec![
+            topic(&format!("{}_foo", SHARD_TOPIC_PREFIX)),
+            topic(&format!("{}X", SHARD_TOPIC_PREFIX)),
+            // no leading `/`, or something before it
+            Topic::new(format!(
+                "{}/{}/{}",
+                TOPIC_PREFIX, BEACON_ATTESTATION_TOPIC, TOPIC_ENCODING_POSTFIX
+            ))
+            .no_hash(),
+            Topic::new(format!(
+                "other/{}/{}/{}",
+                TOPIC_PREFIX, BEACON_ATTESTATION_TOPIC, TOPIC_ENCODING_POSTFIX
+            ))
+            .no_hash(),
+        ];
+        for topic in topics {
+            assert!(!is_known_topic(&topic), "{} should be unknown", topic.as_str());
+            assert_eq!(
+                PubsubMessage::from_topics(&[topic], vec![1]),
+                PubsubMessage::Unknown(vec![1])
+            );
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Splits payloads too large for a single gossip message into chunks, and reassembles them on
+//! receipt.
+//!
+//! Each chunk is published as its own gossip message, made of a header followed by the chunk's
+//! data. The header is `magic (4 bytes) | id (8 bytes) | index (2 bytes) | total (2 bytes)`, with
+//! integers big-endian. Only messages on topics configured as `chunked_topics` are read as
+//! chunks, so ordinary gossip that happens to start with the magic bytes is left alone.
+
+use crate::clock;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Marks a gossip message as a chunk of a larger message.
+const CHUNK_MAGIC: [u8; 4] = *b"MCHK";
+/// The length of the header at the start of each chunk.
+pub const CHUNK_HEADER_LEN: usize = 16;
+/// How long to wait for all chunks of a message before discarding those received.
+pub const CHUNK_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// The maximum number of incomplete messages held. Chunks of further messages are dropped.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+/// The maximum number of bytes held across all incomplete messages.
+pub const MAX_PARTIAL_BYTES: usize = 64 * 1024 * 1024;
+/// The maximum number of bytes held across the incomplete messages of a single source, so that
+/// one peer cannot take the whole of `MAX_PARTIAL_BYTES`.
+pub const MAX_PARTIAL_BYTES_PER_SOURCE: usize = 16 * 1024 * 1024;
+
+/// A chunk of a larger message, borrowed from the gossip message carrying it.
+pub struct Chunk<'a> {
+    /// Identifies the message the chunk belongs to, together with the message's source.
+    id: u64,
+    /// The chunk's position in the message.
+    index: u16,
+    /// The number of chunks in the message.
+    total: u16,
+    /// The chunk's part of the message.
+    data: &'a [u8],
+}
+
+/// Splits a message into gossip messages carrying at most `chunk_size` bytes of it each.
+pub fn split(id: u64, data: &[u8], chunk_size: usize) -> Result<Vec<Vec<u8>>, String> {
+    if chunk_size == 0 {
+        return Err("Chunk size must be greater than zero".into());
+    }
+    let total = std::cmp::max((data.len() + chunk_size - 1) / chunk_size, 1);
+    if total > u16::max_value() as usize {
+        return Err(format!(
+            "Message needs {} chunks, more than the maximum of {}",
+            total,
+            u16::max_value()
+        ));
+    }
+    Ok((0..total)
+        .map(|index| {
+            let start = index * chunk_size;
+            let end = std::cmp::min(start + chunk_size, data.len());
+            let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + end - start);
+            chunk.extend_from_slice(&CHUNK_MAGIC);
+            chunk.extend_from_slice(&id.to_be_bytes());
+            chunk.extend_from_slice(&(index as u16).to_be_bytes());
+            chunk.extend_from_slice(&(total as u16).to_be_bytes());
+            chunk.extend_from_slice(&data[start..end]);
+            chunk
+        })
+        .collect())
+}
+
+/// Reads a gossip message as a chunk, if it is one.
+pub fn decode_chunk(message: &[u8]) -> Option<Chunk> {
+    if message.len() < CHUNK_HEADER_LEN || message[..4] != CHUNK_MAGIC {
+        return None;
+    }
+    let mut id = [0; 8];
+    id.copy_from_slice(&message[4..12]);
+    let index = u16::from_be_bytes([message[12], message[13]]);
+    let total = u16::from_be_bytes([message[14], message[15]]);
+    if index >= total {
+        return None;
+    }
+    Some(Chunk {
+        id: u64::from_be_bytes(id),
+        index,
+        total,
+        data: &message[CHUNK_HEADER_LEN..],
+    })
+}
+
+/// Collects chunks until their message is complete.
+pub struct ChunkReassembler {
+    /// How long to keep an incomplete message.
+    timeout: Duration,
+    /// Incomplete messages, by source and id.
+    partial: HashMap<(PeerId, u64), PartialMessage>,
+    /// The maximum number of bytes held across all incomplete messages.
+    max_bytes: usize,
+    /// The maximum number of bytes held for a single source.
+    max_bytes_per_source: usize,
+    /// The number of bytes held across all incomplete messages.
+    bytes: usize,
+    /// The number of bytes held for each source with incomplete messages.
+    source_bytes: HashMap<PeerId, usize>,
+}
+
+/// The chunks of a message received so far.
+struct PartialMessage {
+    /// When the first chunk arrived.
+    started: Instant,
+    /// The chunks, by index.
+    chunks: Vec<Option<Vec<u8>>>,
+    /// The number of chunks received.
+    received: usize,
+    /// The number of bytes received.
+    bytes: usize,
+}
+
+impl ChunkReassembler {
+    pub fn new(timeout: Duration, max_bytes: usize, max_bytes_per_source: usize) -> Self {
+        ChunkReassembler {
+            timeout,
+            partial: HashMap::new(),
+            max_bytes,
+            max_bytes_per_source,
+            bytes: 0,
+            source_bytes: HashMap::new(),
+        }
+    }
+
+    /// Adds a chunk, returning the whole message once all of its chunks have arrived. Chunks
+    /// that would take the held bytes over either limit are dropped.
+    pub fn add(&mut self, source: &PeerId, chunk: Chunk) -> Option<Vec<u8>> {
+        self.remove_expired();
+        let key = (source.clone(), chunk.id);
+        if !self.partial.contains_key(&key) && self.partial.len() >= MAX_PARTIAL_MESSAGES {
+            return None;
+        }
+        let size = chunk.data.len();
+        let source_bytes = self.source_bytes.get(source).cloned().unwrap_or(0);
+        if self.bytes + size > self.max_bytes || source_bytes + size > self.max_bytes_per_source {
+            return None;
+        }
+        let partial = self
+            .partial
+            .entry(key.clone())
+            .or_insert_with(|| PartialMessage {
+                started: clock::now(),
+                chunks: vec![None; chunk.total as usize],
+                received: 0,
+                bytes: 0,
+            });
+        // chunks disagreeing on the size of their message cannot be reassembled
+        if partial.chunks.len() != chunk.total as usize {
+            return None;
+        }
+        let slot = &mut partial.chunks[chunk.index as usize];
+        if slot.is_none() {
+            *slot = Some(chunk.data.to_vec());
+            partial.received += 1;
+            partial.bytes += size;
+            self.bytes += size;
+            *self.source_bytes.entry(source.clone()).or_insert(0) += size;
+        }
+        if partial.received < partial.chunks.len() {
+            return None;
+        }
+        self.remove(&key)
+            .map(|partial| partial.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    /// The number of bytes held across all incomplete messages.
+    pub fn held_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Removes an incomplete message, releasing its bytes.
+    fn remove(&mut self, key: &(PeerId, u64)) -> Option<PartialMessage> {
+        let partial = self.partial.remove(key)?;
+        self.bytes -= partial.bytes;
+        let source_empty = match self.source_bytes.get_mut(&key.0) {
+            Some(bytes) => {
+                *bytes -= partial.bytes;
+                *bytes == 0
+            }
+            None => false,
+        };
+        if source_empty {
+            self.source_bytes.remove(&key.0);
+        }
+        Some(partial)
+    }
+
+    /// Discards messages whose chunks did not all arrive in time.
+    fn remove_expired(&mut self) {
+        let timeout = self.timeout;
+        let now = clock::now();
+        let expired: Vec<(PeerId, u64)> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now.duration_since(partial.started) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::test_utils::*;
+
+    fn reassembler() -> ChunkReassembler {
+        ChunkReassembler::new(CHUNK_REASSEMBLY_TIMEOUT, 100, 40)
+    }
+
+    fn add_all(
+        reassembler: &mut ChunkReassembler,
+        source: &PeerId,
+        chunks: &[Vec<u8>],
+    ) -> Option<Vec<u8>> {
+        let mut result = None;
+        for chunk in chunks {
+            result = reassembler.add(source, decode_chunk(chunk).expect("a chunk"));
+        }
+        result
+    }
+
+    #[test]
+    fn three_chunks_round_trip() {
+        let data: Vec<u8> = (0..25).collect();
+        let chunks = split(7, &data, 10).unwrap();
+        assert_eq!(chunks.len(), 3);
+        let mut reassembler = reassembler();
+        let source = PeerId::random();
+        // chunks may arrive in any order
+        assert!(reassembler.add(&source, decode_chunk(&chunks[2]).unwrap()).is_none());
+        assert!(reassembler.add(&source, decode_chunk(&chunks[0]).unwrap()).is_none());
+        assert_eq!(reassembler.add(&source, decode_chunk(&chunks[1]).unwrap()), Some(data));
+        assert_eq!(reassembler.held_bytes(), 0);
+    }
+
+    #[test]
+    fn held_bytes_are_capped_per_source_and_in_total() {
+        let mut reassembler = reassembler();
+        let greedy = PeerId::random();
+        // 40 bytes fills the source's allowance, so the last chunk of a 50 byte message is
+        // dropped
+        let chunks = split(1, &[0; 50], 10).unwrap();
+        assert!(add_all(&mut reassembler, &greedy, &chunks).is_none());
+        assert_eq!(reassembler.held_bytes(), 40);
+
+        let others: Vec<PeerId> = (0..2).map(|_| PeerId::random()).collect();
+        for source in &others {
+            let chunks = split(1, &[0; 40], 10).unwrap();
+            add_all(&mut reassembler, source, &chunks[..3]);
+        }
+        // 100 bytes are held in total, so a new source cannot add anything
+        assert_eq!(reassembler.held_bytes(), 100);
+        let late = split(1, &[0; 10], 10).unwrap();
+        assert!(add_all(&mut reassembler, &PeerId::random(), &late).is_none());
+
+        // at the limit even the chunk that would complete a message is dropped
+        let chunks = split(1, &[0; 40], 10).unwrap();
+        assert!(add_all(&mut reassembler, &others[0], &chunks[3..]).is_none());
+        assert_eq!(reassembler.held_bytes(), 100);
+    }
+
+    #[test]
+    fn expired_messages_release_their_bytes() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut reassembler = reassembler();
+            let chunks = split(1, &[0; 30], 10).unwrap();
+            add_all(&mut reassembler, &PeerId::random(), &chunks[..2]);
+            assert_eq!(reassembler.held_bytes(), 20);
+
+            clock.advance(CHUNK_REASSEMBLY_TIMEOUT);
+            let source = PeerId::random();
+            let fresh = split(2, &[0; 10], 10).unwrap();
+            assert_eq!(add_all(&mut reassembler, &source, &fresh), Some(vec![0; 10]));
+            assert_eq!(reassembler.held_bytes(), 0);
+        });
+    }
+}
@@ -0,0 +1,71 @@
+//! The time source for the network's timing logic.
+//!
+//! Timing code reads the time through `now`, which asks the clock of the tokio runtime it runs on,
+//! as tokio's own timers do. Runtimes use the system clock unless built with another one. A
+//! runtime built with a `MockClock` (see `tokio::runtime::Builder::clock`) runs the network on
+//! time that only moves when the mock clock is advanced, making timeouts, backoffs and expiry
+//! deterministic under test.
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub use tokio_timer::clock::Now as Clock;
+
+/// The current time, according to the current runtime's clock.
+pub fn now() -> Instant {
+    tokio_timer::clock::now()
+}
+
+/// A clock that only moves when advanced. Clones share the same time.
+#[derive(Clone)]
+pub struct MockClock {
+    /// The time the clock started at.
+    start: Instant,
+    /// How far the clock has been advanced.
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::run_with_clock;
+
+    #[test]
+    fn now_only_moves_when_mock_clock_advances() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let start = now();
+            assert_eq!(now(), start);
+            clock.advance(Duration::from_secs(5));
+            assert_eq!(now() - start, Duration::from_secs(5));
+        });
+    }
+}
@@ -1,10 +1,12 @@
 use clap::ArgMatches;
 use enr::Enr;
 use libp2p::gossipsub::{GossipsubConfig, GossipsubConfigBuilder};
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, PeerId};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// The gossipsub topic names.
 // These constants form a topic name of the form /TOPIC_PREFIX/TOPIC/ENCODING_POSTFIX
@@ -17,6 +19,13 @@ pub const VOLUNTARY_EXIT_TOPIC: &str = "voluntary_exit";
 pub const PROPOSER_SLASHING_TOPIC: &str = "proposer_slashing";
 pub const ATTESTER_SLASHING_TOPIC: &str = "attester_slashing";
 pub const SHARD_TOPIC_PREFIX: &str = "shard";
+/// Carries timestamped probes used to measure gossip propagation latency.
+pub const PROBE_TOPIC: &str = "mothra_probe";
+// Attestation subnet topics are of the form
+// /TOPIC_PREFIX/COMMITTEE_INDEX_TOPIC_PREFIX{subnet_id}_BEACON_ATTESTATION_TOPIC/ENCODING_POSTFIX
+pub const COMMITTEE_INDEX_TOPIC_PREFIX: &str = "committee_index";
+/// The number of attestation subnets.
+pub const ATTESTATION_SUBNET_COUNT: u64 = 64;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(default)]
@@ -40,6 +49,47 @@ pub struct Config {
     /// Target number of connected peers.
     pub max_peers: usize,
 
+    /// The maximum number of connected peers that may share an IPv4 /24 or IPv6 /48 prefix.
+    /// Keeping the peer set spread across subnets makes eclipse attacks harder.
+    pub max_peers_per_subnet: Option<usize>,
+
+    /// Which peer to disconnect when a new connection takes us over `max_peers`. When unset, no
+    /// peer is evicted and connections beyond the limit are kept.
+    pub eviction_strategy: Option<EvictionStrategy>,
+
+    /// The maximum number of RPC substreams open across all connections. While at the limit, new
+    /// RPC requests fail with an RPC error for their id, and new inbound requests are dropped and
+    /// reported as `InboundRefused`. Only RPC substreams are counted; gossipsub, identify and
+    /// ping substreams are not limited.
+    pub max_open_substreams: Option<usize>,
+
+    /// Socket buffer and multiplexer window sizes used for connections.
+    pub transport_buffers: TransportBuffers,
+
+    /// How long to wait for the response to an RPC request, by protocol message name (e.g.
+    /// `hello`). Protocols that are not listed use the default RPC response timeout.
+    pub rpc_timeouts: HashMap<String, Duration>,
+
+    /// Limits how much each peer can have served to it over RPC. Requests from a peer that has
+    /// used up its budget are held back until the budget replenishes. A request is answered
+    /// with a server error instead if the peer already has 16 requests held, or if its budget
+    /// will not replenish within half of the request's response timeout.
+    pub serving_budget: Option<ServingBudget>,
+
+    /// How long to wait before re-dialing a peer that disconnected unexpectedly. Peers are not
+    /// re-dialed when unset.
+    pub reconnect_delay: Option<Duration>,
+
+    /// The number of times to try re-dialing a peer before giving up on it.
+    pub max_reconnect_attempts: u32,
+
+    /// The maximum number of identify-provided addresses kept for dialing each peer.
+    pub max_dial_addrs_per_peer: usize,
+
+    /// Whether identify-provided addresses in private or link-local ranges may be dialed.
+    /// Loopback addresses are never dialed.
+    pub allow_private_dial_addrs: bool,
+
     /// Gossipsub configuration parameters.
     #[serde(skip)]
     pub gs_config: GossipsubConfig,
@@ -47,6 +97,12 @@ pub struct Config {
     /// List of nodes to initially connect to.
     pub boot_nodes: Vec<Enr>,
 
+    /// The largest ENR, in bytes, accepted from peers and boot nodes. Larger boot node ENRs,
+    /// and larger ENRs added through `add_enr`, are not added to the routing table. Discovered
+    /// ENRs have already been inserted by discv5 when we see them, so larger ones are instead
+    /// never dialed.
+    pub max_enr_size: usize,
+
     /// List of libp2p nodes to initially connect to.
     pub libp2p_nodes: Vec<Multiaddr>,
 
@@ -55,6 +111,115 @@ pub struct Config {
 
     /// List of extra topics to initially subscribe to as strings.
     pub topics: Vec<String>,
+
+    /// Derive the network key from this seed instead of loading it from disk, so the node's peer
+    /// id is known in advance. Intended for tests only: the key is trivially guessable. Seeded
+    /// keys are not saved. Only available with the `test-utils` feature.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub key_seed: Option<u64>,
+
+    /// Optional file containing a separate secp256k1 key whose peer id is set as the author of
+    /// our gossipsub messages. If the file does not exist a new key is generated and written to
+    /// it. The gossipsub version in use does not sign messages, so the key only sets the author
+    /// id and receivers cannot verify it.
+    ///
+    /// By default messages are authored by the network identity, which lets anyone link a
+    /// message's author to our node id. A separate key hides that link. Interop caveat: peers
+    /// see messages authored by a peer id they never connect to, so any peer that expects
+    /// message authors to be connected peers (e.g. to score them) will not be able to.
+    pub gossip_key_file: Option<PathBuf>,
+
+    /// A scheduled maintenance window, as a start and end time. Between the two the node drains
+    /// its connections, and it resumes normal operation afterwards.
+    #[serde(skip)]
+    pub maintenance_window: Option<(Instant, Instant)>,
+
+    /// The maximum number of gossip messages handed to the application for validation at once.
+    /// When set, the application must call `Service::validation_complete` with the id of each
+    /// message it receives, and further messages are held back until a slot frees up. While
+    /// messages are held back the service stops reading from the network, so peers are slowed
+    /// by backpressure.
+    pub max_concurrent_validations: Option<usize>,
+
+    /// The maximum number of gossip messages on a topic handed to the application for
+    /// validation at once, keyed by topic. Messages on one topic wait only for that topic's
+    /// slots, within `max_concurrent_validations`. As with the global limit, the application
+    /// must call `Service::validation_complete` for each message it receives.
+    pub topic_validation_concurrency: HashMap<String, usize>,
+
+    /// The number of recent connection lifecycle events kept for debugging. Zero disables the
+    /// history.
+    pub event_history_len: usize,
+
+    /// Maximum size in bytes of inbound gossip messages per topic. Topics that are not listed
+    /// are limited by the gossipsub `max_transmit_size`.
+    pub topic_max_sizes: HashMap<String, usize>,
+
+    /// Topics that carry messages published with `Behaviour::publish_chunked`. Only gossip on
+    /// these topics is read as chunks and reassembled; messages on them that are not chunks are
+    /// dropped.
+    pub chunked_topics: Vec<String>,
+
+    /// Application-specific fields to advertise in the local ENR, keyed by ENR key. Keys used by
+    /// the ENR scheme or by discovery itself are rejected.
+    pub custom_enr_fields: HashMap<String, Vec<u8>>,
+
+    /// Subscribe to the diagnostic probe topic, so that probes published by other nodes are
+    /// received and their propagation latency reported.
+    pub enable_probes: bool,
+
+    /// The maximum number of topics that may be subscribed to at once, including the default
+    /// topics. Further subscriptions are refused.
+    pub max_subscriptions: Option<usize>,
+
+    /// Pause discovery queries once this many messages are waiting for room in the channel to
+    /// the application.
+    pub discovery_pause_events: usize,
+
+    /// Resume paused discovery queries once the messages waiting for the application fall to
+    /// this many. Must be less than `discovery_pause_events`.
+    pub discovery_resume_events: usize,
+
+    /// The most messages kept waiting for room in the channel to the application. Further
+    /// messages are dropped until it catches up. Must be at least `discovery_pause_events`.
+    pub max_undelivered_events: usize,
+
+    /// Log publishes, RPC sends and dials instead of performing them, and neither listen nor run
+    /// discovery, so applications can be exercised without any network I/O.
+    pub dry_run: bool,
+
+    /// What the service does once the application has dropped its message receiver.
+    pub on_consumer_gone: ConsumerGonePolicy,
+
+    /// Decides whether a peer may connect, once its identity has been authenticated and before
+    /// any protocol runs on the connection. Allows permissioned networks with an external trust
+    /// root. All peers are accepted when unset.
+    #[serde(skip)]
+    pub peer_authorizer: Option<PeerAuthorizer>,
+
+    /// Disconnect peers that announce subscriptions to topics we neither subscribe to nor
+    /// recognise, as they are likely on an incompatible network. The recognised topics are the
+    /// topic constants above, attestation subnet topics and shard topics. By default such
+    /// subscriptions are just tracked.
+    pub strict_topic_policy: bool,
+
+    /// Topics whose gossip is still delivered while the application reports that it is syncing.
+    /// Gossip on every other topic is dropped until syncing ends.
+    pub sync_allowed_topics: Vec<String>,
+
+    /// Hold messages published to a topic until at least this many peers subscribe to it. Held
+    /// messages are published once enough peers subscribe, or dropped after
+    /// `publish_hold_timeout`. Messages are published immediately when unset.
+    pub min_publish_peers: Option<usize>,
+
+    /// How long a published message is held waiting for `min_publish_peers` subscribers.
+    pub publish_hold_timeout: Duration,
+
+    /// Close connections to peers that send us no RPC requests, responses or gossip for this
+    /// long, even if they still answer pings. What we send them does not count, and pinned peers
+    /// and peers tagged for sync are kept. Connections are never closed for inactivity when
+    /// unset.
+    pub app_idle_timeout: Option<Duration>,
 }
 
 impl Default for Config {
@@ -70,19 +235,122 @@ impl Default for Config {
             discovery_address: "127.0.0.1".parse().expect("valid ip address"),
             discovery_port: 9000,
             max_peers: 10,
+            max_peers_per_subnet: None,
+            eviction_strategy: None,
+            max_open_substreams: None,
+            serving_budget: None,
+            rpc_timeouts: HashMap::new(),
+            transport_buffers: TransportBuffers::default(),
+            reconnect_delay: None,
+            max_reconnect_attempts: 3,
+            max_dial_addrs_per_peer: 4,
+            allow_private_dial_addrs: false,
 
             gs_config: GossipsubConfigBuilder::new()
                 .max_transmit_size(1_048_576)
                 .heartbeat_interval(Duration::from_secs(20))
                 .build(),
             boot_nodes: vec![],
+            // the limit set by the ENR specification
+            max_enr_size: 300,
             libp2p_nodes: vec![],
             client_version: "0.0.1".to_string(),
             topics: Vec::new(),
+            #[cfg(any(test, feature = "test-utils"))]
+            key_seed: None,
+            gossip_key_file: None,
+            maintenance_window: None,
+            max_concurrent_validations: None,
+            topic_validation_concurrency: HashMap::new(),
+            event_history_len: 100,
+            topic_max_sizes: HashMap::new(),
+            chunked_topics: Vec::new(),
+            custom_enr_fields: HashMap::new(),
+            enable_probes: false,
+            max_subscriptions: None,
+            discovery_pause_events: 1024,
+            discovery_resume_events: 256,
+            max_undelivered_events: 4096,
+            dry_run: false,
+            on_consumer_gone: ConsumerGonePolicy::Shutdown,
+            peer_authorizer: None,
+            strict_topic_policy: false,
+            sync_allowed_topics: Vec::new(),
+            min_publish_peers: None,
+            publish_hold_timeout: Duration::from_secs(60),
+            app_idle_timeout: None,
         }
     }
 }
 
+/// What to do when the application stops receiving messages from the service.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConsumerGonePolicy {
+    /// End the service's event stream, shutting the network down.
+    Shutdown,
+    /// Stop processing network events, leaving connections open but unread so that peers are
+    /// slowed by backpressure.
+    Pause,
+}
+
+/// Chooses the peer to disconnect when we have more than `max_peers` connections. Pinned peers
+/// are never evicted.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EvictionStrategy {
+    /// Evict the most recently connected peer, keeping established connections.
+    Newest,
+    /// Evict the longest connected peer.
+    Oldest,
+    /// Evict the peer with the highest ping round trip time. Peers not yet measured are kept.
+    HighestLatency,
+    /// Evict the peer we have gone longest without hearing from, by an RPC request or response
+    /// or gossip it authored.
+    MostIdle,
+}
+
+/// Authorizes peers connecting to us, or that we connect to. The function is given the peer's id
+/// and the protobuf encoding of the public key it presented, and returns whether the connection
+/// may proceed.
+#[derive(Clone)]
+pub struct PeerAuthorizer(pub Arc<dyn Fn(&PeerId, &[u8]) -> bool + Send + Sync>);
+
+impl PeerAuthorizer {
+    /// Whether the peer may connect.
+    pub fn authorize(&self, peer_id: &PeerId, public_key: &[u8]) -> bool {
+        (self.0)(peer_id, public_key)
+    }
+}
+
+impl std::fmt::Debug for PeerAuthorizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PeerAuthorizer")
+    }
+}
+
+/// Socket buffer and multiplexer window sizes for connections. Sizes left as `None` use the
+/// defaults. Larger sizes improve throughput on links with a high bandwidth-delay product.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransportBuffers {
+    /// The TCP send buffer size, in bytes.
+    pub tcp_send_buffer_size: Option<usize>,
+    /// The TCP receive buffer size, in bytes.
+    pub tcp_recv_buffer_size: Option<usize>,
+    /// The yamux per-stream receive window, in bytes. Must be at least 256KiB, the yamux
+    /// default.
+    pub yamux_receive_window: Option<u32>,
+    /// The number of frames mplex buffers per substream.
+    pub mplex_max_buffer_size: Option<usize>,
+}
+
+/// A per-peer budget of RPC work, measured in response bytes, that replenishes over time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServingBudget {
+    /// The most work a peer can have saved up.
+    pub capacity: u64,
+    /// The work a peer regains each second.
+    pub refill_per_sec: u64,
+}
+
 /// Generates a default Config.
 impl Config {
     pub fn new() -> Self {
@@ -144,6 +412,10 @@ impl Config {
             self.topics = topics_str.split(',').map(|s| s.into()).collect();
         }
 
+        if let Some(gossip_key_str) = args.value_of("gossip-key") {
+            self.gossip_key_file = Some(PathBuf::from(gossip_key_str));
+        }
+
         if let Some(discovery_address_str) = args.value_of("discovery-address") {
             self.discovery_address = discovery_address_str
                 .parse()
@@ -1,3 +1,6 @@
+use crate::clock;
+use crate::config::{EvictionStrategy, ATTESTATION_SUBNET_COUNT};
+use crate::peer_selector::PeerCandidate;
 use crate::{error, NetworkConfig};
 /// This manages the discovery and management of peers.
 ///
@@ -9,15 +12,18 @@ use libp2p::discv5::{Discv5, Discv5Event};
 use libp2p::enr::{Enr, EnrBuilder, NodeId};
 use libp2p::multiaddr::Protocol;
 use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters, ProtocolsHandler};
+use parking_lot::Mutex;
 use slog::{debug, info, warn};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::IpAddr;
 use std::path::Path;
 use std::str::FromStr;
-use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_timer::Delay;
+use tokio_timer::{Delay, DelayQueue};
 
 /// Maximum seconds before searching for extra peers.
 const MAX_TIME_BETWEEN_PEER_SEARCHES: u64 = 60;
@@ -25,6 +31,24 @@ const MAX_TIME_BETWEEN_PEER_SEARCHES: u64 = 60;
 const INITIAL_SEARCH_DELAY: u64 = 5;
 /// Local ENR storage filename.
 const ENR_FILENAME: &str = "enr.dat";
+/// ENR keys that may not be set as custom fields.
+const RESERVED_ENR_KEYS: [&str; 8] = [
+    "id",
+    "secp256k1",
+    "ip",
+    "ip6",
+    "tcp",
+    "tcp6",
+    "udp",
+    ATTNETS_ENR_KEY,
+];
+/// The ENR field advertising which attestation subnets we are subscribed to.
+const ATTNETS_ENR_KEY: &str = "attnets";
+/// How long to remember which peer we expect at an address we are dialing. Dials time out well
+/// before this.
+const DIAL_EXPECTATION_TTL: Duration = Duration::from_secs(60);
+/// The most peers remembered as having oversized ENRs.
+const MAX_OVERSIZED_ENR_PEERS: usize = 1024;
 
 /// Lighthouse discovery behaviour. This provides peer management and discovery using the Discv5
 /// libp2p protocol.
@@ -35,6 +59,88 @@ pub struct Discovery<TSubstream> {
     /// The target number of connected peers on the libp2p interface.
     max_peers: usize,
 
+    /// The maximum number of connected peers sharing a subnet, if limited.
+    max_peers_per_subnet: Option<usize>,
+
+    /// How to pick a peer to disconnect when over `max_peers`.
+    eviction_strategy: Option<EvictionStrategy>,
+
+    /// Peers that are never evicted.
+    pinned_peers: HashSet<PeerId>,
+
+    /// What we know of each connected peer, for choosing which to evict.
+    peer_info: HashMap<PeerId, PeerInfo>,
+
+    /// The subnet of each connected peer.
+    peer_subnets: HashMap<PeerId, IpSubnet>,
+
+    /// The number of connected peers in each subnet.
+    subnet_peer_counts: HashMap<IpSubnet, usize>,
+
+    /// When draining, no new peers are searched for or dialed and new connections are dropped.
+    draining: bool,
+
+    /// Whether searches for new peers are paused while the application catches up on events.
+    queries_paused: bool,
+
+    /// Peers whose connection should be closed by the swarm.
+    peers_to_disconnect: Vec<PeerId>,
+
+    /// Peers that are never dialed and whose connections are always closed.
+    banned_peers: HashSet<PeerId>,
+
+    /// The largest ENR accepted, in bytes.
+    max_enr_size: usize,
+
+    /// Discovered peers whose ENRs exceeded the maximum size, which are not dialed.
+    oversized_enr_peers: HashSet<PeerId>,
+
+    /// Addresses the application asked to dial, waiting for the swarm.
+    addresses_to_dial: VecDeque<Multiaddr>,
+
+    /// The new addresses of connected peers whose connections are being migrated.
+    migrations: HashMap<PeerId, Multiaddr>,
+
+    /// The reason we are disconnecting from each peer we have queued for disconnection.
+    disconnect_reasons: HashMap<PeerId, DisconnectReason>,
+
+    /// Peers we dialed whose connection was rejected, until the dial's report is suppressed.
+    rejected_dials: HashSet<PeerId>,
+
+    /// How long to wait before re-dialing a peer that disconnected unexpectedly, if at all.
+    reconnect_delay: Option<Duration>,
+
+    /// The number of re-dials attempted for a peer before giving up.
+    max_reconnect_attempts: u32,
+
+    /// Peers waiting to be re-dialed.
+    reconnects: DelayQueue<PeerId>,
+
+    /// The number of re-dials made for each peer we are trying to reconnect to.
+    reconnect_attempts: HashMap<PeerId, u32>,
+
+    /// The addresses we dialed peers on, kept while connected or reconnecting so that peers
+    /// which are not in the routing table can be re-dialed.
+    peer_addresses: HashMap<PeerId, Multiaddr>,
+
+    /// The maximum number of identify-provided addresses kept per peer.
+    max_dial_addrs_per_peer: usize,
+
+    /// Whether private identify-provided addresses are kept.
+    allow_private_dial_addrs: bool,
+
+    /// Addresses peers advertised through identify that passed filtering.
+    identified_addresses: HashMap<PeerId, Vec<Multiaddr>>,
+
+    /// The peers we expect to reach at the addresses we dial, checked by the transport.
+    dial_expectations: DialExpectations,
+
+    /// Connection lifecycle events not yet collected by the service.
+    connection_events: Vec<ConnectionLogEntry>,
+
+    /// Bitfield of the attestation subnets advertised in our ENR.
+    attnets: u64,
+
     /// directory to save ENR to
     enr_dir: String,
 
@@ -45,13 +151,17 @@ pub struct Discovery<TSubstream> {
     /// time is reached.
     past_discovery_delay: u64,
 
+    /// The UDP port discv5 is bound to.
+    udp_port: u16,
+
     /// The TCP port for libp2p. Used to convert an updated IP address to a multiaddr. Note: This
     /// assumes that the external TCP port is the same as the internal TCP port if behind a NAT.
     //TODO: Improve NAT handling limit the above restriction
     tcp_port: u16,
 
-    /// The discovery behaviour used to discover new peers.
-    discovery: Discv5<TSubstream>,
+    /// The discovery behaviour used to discover new peers. This is `None` once discovery has been
+    /// closed.
+    discovery: Option<Discv5<TSubstream>>,
 
     /// Logger for the discovery behaviour.
     log: slog::Logger,
@@ -61,49 +171,67 @@ impl<TSubstream> Discovery<TSubstream> {
     pub fn new(
         local_key: &Keypair,
         config: &NetworkConfig,
+        dial_expectations: DialExpectations,
         log: &slog::Logger,
     ) -> error::Result<Self> {
         let log = log.clone();
-
-        // checks if current ENR matches that found on disk
-        let local_enr = load_enr(local_key, config, &log)?;
+        let discovery = start_discv5(local_key, config, &log)?;
 
         let enr_dir = match config.network_dir.to_str() {
             Some(path) => String::from(path),
             None => String::from(""),
         };
 
-        info!(log, "ENR Initialised"; "ENR" => local_enr.to_base64(), "Seq" => local_enr.seq());
-        debug!(log, "Discv5 Node ID Initialised"; "node_id" => format!("{}",local_enr.node_id()));
-
-        let mut discovery = Discv5::new(local_enr, local_key.clone(), config.listen_address)
-            .map_err(|e| format!("Discv5 service failed. Error: {:?}", e))?;
-
-        // Add bootnodes to routing table
-        for bootnode_enr in config.boot_nodes.clone() {
-            debug!(
-                log,
-                "Adding node to routing table";
-                "Node ID" => format!("{}",
-                bootnode_enr.node_id())
-            );
-            discovery.add_enr(bootnode_enr);
-        }
+        // carry on advertising the subnets of an ENR loaded from disk
+        let attnets = discovery
+            .local_enr()
+            .get(ATTNETS_ENR_KEY)
+            .map(|field| decode_attnets(field))
+            .unwrap_or(0);
 
         Ok(Self {
             connected_peers: HashSet::new(),
             max_peers: config.max_peers,
-            peer_discovery_delay: Delay::new(Instant::now()),
+            max_peers_per_subnet: config.max_peers_per_subnet,
+            eviction_strategy: config.eviction_strategy,
+            pinned_peers: HashSet::new(),
+            peer_info: HashMap::new(),
+            peer_subnets: HashMap::new(),
+            subnet_peer_counts: HashMap::new(),
+            draining: false,
+            queries_paused: false,
+            attnets,
+            peers_to_disconnect: Vec::new(),
+            banned_peers: HashSet::new(),
+            max_enr_size: config.max_enr_size,
+            oversized_enr_peers: HashSet::new(),
+            addresses_to_dial: VecDeque::new(),
+            migrations: HashMap::new(),
+            disconnect_reasons: HashMap::new(),
+            rejected_dials: HashSet::new(),
+            connection_events: Vec::new(),
+            reconnect_delay: config.reconnect_delay,
+            max_reconnect_attempts: config.max_reconnect_attempts,
+            reconnects: DelayQueue::new(),
+            reconnect_attempts: HashMap::new(),
+            peer_addresses: HashMap::new(),
+            max_dial_addrs_per_peer: config.max_dial_addrs_per_peer,
+            allow_private_dial_addrs: config.allow_private_dial_addrs,
+            identified_addresses: HashMap::new(),
+            dial_expectations,
+            peer_discovery_delay: Delay::new(clock::now()),
             past_discovery_delay: INITIAL_SEARCH_DELAY,
+            udp_port: config.discovery_port,
             tcp_port: config.libp2p_port,
-            discovery,
+            discovery: Some(discovery),
             log,
             enr_dir,
         })
     }
 
-    pub fn local_enr(&self) -> &Enr {
-        self.discovery.local_enr()
+    /// The local ENR, if discovery has not been closed.
+    pub fn local_enr(&self) -> Option<&Enr> {
+        self.discovery.as_ref().map(|discovery| discovery.local_enr())
     }
 
     /// Manually search for peers. This restarts the discovery round, sparking multiple rapid
@@ -113,9 +241,14 @@ impl<TSubstream> Discovery<TSubstream> {
         self.find_peers();
     }
 
-    /// Add an Enr to the routing table of the discovery mechanism.
+    /// Add an Enr to the routing table of the discovery mechanism, unless it is oversized.
     pub fn add_enr(&mut self, enr: Enr) {
-        self.discovery.add_enr(enr);
+        if self.is_oversized(&enr) {
+            return;
+        }
+        if let Some(discovery) = self.discovery.as_mut() {
+            discovery.add_enr(enr);
+        }
     }
 
     /// The current number of connected libp2p peers.
@@ -123,19 +256,523 @@ impl<TSubstream> Discovery<TSubstream> {
         self.connected_peers.len()
     }
 
+    /// The number of peers known to discovery, i.e. the number of ENRs in the routing table.
+    pub fn known_peer_count(&self) -> usize {
+        self.discovery
+            .as_ref()
+            .map_or(0, |discovery| discovery.enr_entries().count())
+    }
+
+    /// The number of routing table entries in each bucket, where bucket `i` holds the nodes at
+    /// log2 distance `i + 1` from us. A lopsided distribution points to discovery problems or an
+    /// eclipse attempt.
+    pub fn bucket_stats(&self) -> Vec<usize> {
+        let mut buckets = vec![0; 256];
+        let discovery = match self.discovery.as_ref() {
+            Some(discovery) => discovery,
+            None => return buckets,
+        };
+        let local_id = discovery.local_enr().node_id().raw();
+        for enr in discovery.enr_entries() {
+            if let Some(bucket) = bucket_index(&local_id, &enr.node_id().raw()) {
+                buckets[bucket] += 1;
+            }
+        }
+        buckets
+    }
+
+    /// The value of a field in a peer's ENR, if the peer is in the routing table and its ENR has
+    /// the field.
+    pub fn peer_enr_field(&self, peer_id: &PeerId, key: &str) -> Option<Vec<u8>> {
+        self.discovery
+            .as_ref()?
+            .enr_entries()
+            .find(|enr| enr.peer_id() == *peer_id)
+            .and_then(|enr| enr.get(key).cloned())
+    }
+
+    /// Records the listen addresses a peer advertised through identify, so they can be dialed
+    /// later. Self-advertised addresses are untrusted: a peer could point us at a victim, so
+    /// loopback and non-IP addresses are dropped, private ranges are dropped unless allowed and
+    /// only a few addresses are kept.
+    pub fn add_identified_addresses(&mut self, peer_id: &PeerId, addresses: Vec<Multiaddr>) {
+        let allow_private = self.allow_private_dial_addrs;
+        let mut dialable: Vec<Multiaddr> = Vec::new();
+        for address in addresses {
+            if dialable.len() >= self.max_dial_addrs_per_peer {
+                break;
+            }
+            if !dialable_address(&address, allow_private) {
+                debug!(self.log, "Ignoring identified address"; "peer_id" => format!("{:?}", peer_id), "address" => format!("{}", address));
+                continue;
+            }
+            if !dialable.contains(&address) {
+                dialable.push(address);
+            }
+        }
+        self.migrate_if_moved(peer_id, &dialable);
+        if dialable.is_empty() {
+            self.identified_addresses.remove(peer_id);
+        } else {
+            self.identified_addresses.insert(peer_id.clone(), dialable);
+        }
+    }
+
+    /// Opens a new connection to a connected peer whose address has changed, as when it roams or
+    /// its NAT rebinds, so that it replaces the old connection before that fails. Only peers we
+    /// dialed are migrated, as we have no dialable address for the others.
+    fn migrate_if_moved(&mut self, peer_id: &PeerId, addresses: &[Multiaddr]) {
+        if !self.connected_peers.contains(peer_id) || self.banned_peers.contains(peer_id) {
+            return;
+        }
+        let current = match self.peer_addresses.get(peer_id) {
+            Some(current) => current,
+            None => return,
+        };
+        let new_address = match addresses.first() {
+            Some(address) if !addresses.contains(current) => address,
+            _ => return,
+        };
+        if self.migrations.get(peer_id) == Some(new_address) {
+            return;
+        }
+        info!(self.log, "Peer address changed, migrating connection"; "peer_id" => format!("{:?}", peer_id), "old" => format!("{}", current), "new" => format!("{}", new_address));
+        self.dial_expectations
+            .expect(new_address.clone(), peer_id.clone());
+        self.migrations.insert(peer_id.clone(), new_address.clone());
+        self.addresses_to_dial.push_back(new_address.clone());
+    }
+
+    /// Whether an ENR exceeds the maximum size, logging and remembering its peer if it does.
+    fn is_oversized(&mut self, enr: &Enr) -> bool {
+        let size = enr_size(enr);
+        if size <= self.max_enr_size {
+            return false;
+        }
+        warn!(self.log, "Rejecting oversized ENR"; "node_id" => format!("{}", enr.node_id()), "size" => size, "max" => self.max_enr_size);
+        if self.oversized_enr_peers.len() < MAX_OVERSIZED_ENR_PEERS {
+            self.oversized_enr_peers.insert(enr.peer_id());
+        }
+        true
+    }
+
     /// The current number of connected libp2p peers.
     pub fn connected_peer_set(&self) -> &HashSet<PeerId> {
         &self.connected_peers
     }
 
+    /// The bitfield of attestation subnets advertised in our ENR.
+    pub fn attnets(&self) -> u64 {
+        self.attnets
+    }
+
+    /// Sets or clears an attestation subnet's bit in the local ENR's `attnets` field. Changing
+    /// the ENR bumps its sequence number, which peers learn of on their next discv5 exchange with
+    /// us, and the updated ENR is saved to disk.
+    pub fn update_attnets(&mut self, subnet_id: u64, subscribed: bool) -> Result<(), String> {
+        if subnet_id >= ATTESTATION_SUBNET_COUNT {
+            return Err(format!("Invalid attestation subnet: {}", subnet_id));
+        }
+        let attnets = if subscribed {
+            self.attnets | (1 << subnet_id)
+        } else {
+            self.attnets & !(1 << subnet_id)
+        };
+        if attnets == self.attnets {
+            return Ok(());
+        }
+        let discovery = self
+            .discovery
+            .as_mut()
+            .ok_or_else(|| "Discovery is closed".to_string())?;
+        // the SSZ bitvector encoding of the field orders bits from the least significant
+        discovery
+            .enr_insert(ATTNETS_ENR_KEY, attnets.to_le_bytes().to_vec())
+            .map_err(|e| format!("Could not update ENR attnets: {:?}", e))?;
+        self.attnets = attnets;
+
+        let enr = discovery.local_enr();
+        info!(self.log, "ENR attnets updated"; "attnets" => format!("{:#018x}", attnets), "Seq" => enr.seq());
+        save_enr_to_disc(Path::new(&self.enr_dir), enr, &self.log);
+        Ok(())
+    }
+
+    /// Sets the IP address advertised in the local ENR, saving the updated ENR to disk.
+    pub fn update_external_ip(&mut self, ip: IpAddr) -> Result<(), String> {
+        let discovery = self
+            .discovery
+            .as_mut()
+            .ok_or_else(|| "Discovery is closed".to_string())?;
+        if discovery.local_enr().ip() == Some(ip) {
+            return Ok(());
+        }
+        let (key, octets) = match ip {
+            IpAddr::V4(ip) => ("ip", ip.octets().to_vec()),
+            IpAddr::V6(ip) => ("ip6", ip.octets().to_vec()),
+        };
+        discovery
+            .enr_insert(key, octets)
+            .map_err(|e| format!("Could not update ENR address: {:?}", e))?;
+
+        let enr = discovery.local_enr();
+        info!(self.log, "ENR address updated"; "IP" => format!("{}", ip), "Seq" => enr.seq());
+        save_enr_to_disc(Path::new(&self.enr_dir), enr, &self.log);
+        Ok(())
+    }
+
+    /// Enters or leaves drain mode. Existing connections are kept, but while draining no new
+    /// peers are dialed and new connections are closed.
+    pub fn set_draining(&mut self, draining: bool) {
+        self.draining = draining;
+    }
+
+    /// Whether discovery is currently draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Exempts a peer from eviction.
+    pub fn pin_peer(&mut self, peer_id: PeerId) {
+        self.pinned_peers.insert(peer_id);
+    }
+
+    /// Makes a pinned peer eligible for eviction again.
+    pub fn unpin_peer(&mut self, peer_id: &PeerId) {
+        self.pinned_peers.remove(peer_id);
+    }
+
+    /// The peers exempt from eviction.
+    pub fn pinned_peers(&self) -> &HashSet<PeerId> {
+        &self.pinned_peers
+    }
+
+    /// Tags a connected peer with the purpose of its connection. Tagged peers are evicted only
+    /// once no untagged peers remain. The tag is cleared when the peer disconnects.
+    pub fn tag_connection(&mut self, peer_id: &PeerId, tag: ConnectionTag) {
+        if let Some(info) = self.peer_info.get_mut(peer_id) {
+            info.tag = Some(tag);
+        }
+    }
+
+    /// Removes the tag from a connected peer.
+    pub fn untag_connection(&mut self, peer_id: &PeerId) {
+        if let Some(info) = self.peer_info.get_mut(peer_id) {
+            info.tag = None;
+        }
+    }
+
+    /// Records a ping round trip time to a connected peer.
+    pub fn record_latency(&mut self, peer_id: &PeerId, rtt: Duration) {
+        if let Some(info) = self.peer_info.get_mut(peer_id) {
+            info.latency = Some(rtt);
+        }
+    }
+
+    /// Records RPC or gossip traffic from a connected peer.
+    pub fn record_activity(&mut self, peer_id: &PeerId) {
+        if let Some(info) = self.peer_info.get_mut(peer_id) {
+            info.last_active = clock::now();
+        }
+    }
+
+    /// What is known of each connected peer, for choosing between them. Progress is not known
+    /// to discovery and is left unset.
+    pub fn peer_candidates(&self) -> Vec<PeerCandidate> {
+        self.peer_info
+            .iter()
+            .map(|(peer_id, info)| PeerCandidate {
+                peer_id: peer_id.clone(),
+                connected_at: info.connected_at,
+                latency: info.latency,
+                last_active: info.last_active,
+                tag: info.tag,
+                progress: None,
+            })
+            .collect()
+    }
+
+    /// The peer to disconnect according to the eviction strategy, if any peer can be evicted.
+    /// Pinned peers and peers already being disconnected are not considered, and tagged peers
+    /// are only considered when all remaining candidates are tagged.
+    fn eviction_candidate(&self, strategy: EvictionStrategy) -> Option<PeerId> {
+        let evictable = |peer_id: &PeerId| {
+            !self.pinned_peers.contains(peer_id) && !self.disconnect_reasons.contains_key(peer_id)
+        };
+        let lowest_tag = self
+            .peer_info
+            .iter()
+            .filter(|(peer_id, _)| evictable(peer_id))
+            .map(|(_, info)| info.tag)
+            .min()?;
+        let candidates = self
+            .peer_info
+            .iter()
+            .filter(|(peer_id, info)| evictable(peer_id) && info.tag == lowest_tag);
+        let candidate = match strategy {
+            EvictionStrategy::Newest => candidates.max_by_key(|(_, info)| info.connected_at),
+            EvictionStrategy::Oldest => candidates.min_by_key(|(_, info)| info.connected_at),
+            EvictionStrategy::HighestLatency => {
+                candidates.max_by_key(|(_, info)| info.latency)
+            }
+            EvictionStrategy::MostIdle => candidates.min_by_key(|(_, info)| info.last_active),
+        };
+        candidate.map(|(peer_id, _)| peer_id.clone())
+    }
+
+    /// Queues an address to be dialed by the swarm.
+    pub fn dial(&mut self, address: Multiaddr) {
+        self.addresses_to_dial.push_back(address);
+    }
+
+    /// Stops a peer from connecting to us or being dialed, disconnecting it if connected. Bans
+    /// last for the life of the node.
+    pub fn ban_peer(&mut self, peer_id: PeerId) {
+        info!(self.log, "Banning peer"; "peer_id" => format!("{:?}", peer_id));
+        self.abandon_reconnect(&peer_id);
+        if self.connected_peers.contains(&peer_id) {
+            self.disconnect_peer(peer_id.clone(), DisconnectReason::Banned);
+        }
+        self.banned_peers.insert(peer_id);
+    }
+
+    /// Whether a peer has been banned.
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.banned_peers.contains(peer_id)
+    }
+
+    /// The peers that have been banned.
+    pub fn banned_peers(&self) -> &HashSet<PeerId> {
+        &self.banned_peers
+    }
+
+    /// Queues a peer to be disconnected by the swarm.
+    pub fn disconnect_peer(&mut self, peer_id: PeerId, reason: DisconnectReason) {
+        self.disconnect_reasons.insert(peer_id.clone(), reason);
+        self.peers_to_disconnect.push(peer_id);
+    }
+
+    /// Queues peers that have sent us no RPC or gossip for `timeout` for disconnection. Pinned
+    /// peers and peers tagged for sync are kept. Returns when the next of the remaining peers
+    /// would become idle.
+    pub fn disconnect_app_idle_peers(&mut self, timeout: Duration) -> Option<Instant> {
+        let now = clock::now();
+        let idle: Vec<PeerId> = self
+            .peer_info
+            .iter()
+            .filter(|(peer_id, info)| {
+                now.duration_since(info.last_active) >= timeout
+                    && info.tag != Some(ConnectionTag::Sync)
+                    && !self.pinned_peers.contains(*peer_id)
+                    && !self.peers_to_disconnect.contains(*peer_id)
+            })
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        for peer_id in idle {
+            debug!(self.log, "Disconnecting application idle peer"; "peer_id" => format!("{}", peer_id));
+            self.disconnect_peer(peer_id, DisconnectReason::AppIdle);
+        }
+        let pinned_peers = &self.pinned_peers;
+        let peers_to_disconnect = &self.peers_to_disconnect;
+        self.peer_info
+            .iter()
+            .filter(|(peer_id, info)| {
+                info.tag != Some(ConnectionTag::Sync)
+                    && !pinned_peers.contains(*peer_id)
+                    && !peers_to_disconnect.contains(*peer_id)
+            })
+            .map(|(_, info)| info.last_active + timeout)
+            .min()
+    }
+
+    /// Whether any peers are waiting to be disconnected.
+    pub fn has_peers_to_disconnect(&self) -> bool {
+        !self.peers_to_disconnect.is_empty()
+    }
+
+    /// Cleans up after a peer's connection has closed. Connections closed by the swarm on our
+    /// request are not reported through `inject_disconnected`, so this is also called directly.
+    ///
+    /// Returns whether the peer was connected.
+    pub fn peer_disconnected(&mut self, peer_id: &PeerId) -> bool {
+        let was_connected = self.connected_peers.remove(peer_id);
+        self.peer_info.remove(peer_id);
+        if let Some(subnet) = self.peer_subnets.remove(peer_id) {
+            if let Some(count) = self.subnet_peer_counts.get_mut(&subnet) {
+                *count -= 1;
+                if *count == 0 {
+                    self.subnet_peer_counts.remove(&subnet);
+                }
+            }
+        }
+        let reason = self.disconnect_reasons.remove(peer_id);
+        // connections we did not close ourselves dropped unexpectedly and are worth restoring
+        let unexpected = was_connected && reason.is_none();
+        if was_connected || reason.is_some() {
+            let reason = reason.unwrap_or(DisconnectReason::Remote);
+            self.record_connection_event(peer_id.clone(), ConnectionEvent::Disconnected(reason));
+        }
+        if unexpected {
+            self.schedule_reconnect(peer_id.clone());
+        } else {
+            self.forget_addresses(peer_id);
+        }
+        was_connected
+    }
+
+    /// Records a new connection, queueing it for disconnection if the peer is banned, we are
+    /// draining or its subnet is full. Returns whether the connection was accepted.
+    fn accept_connection(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) -> bool {
+        self.record_connection_event(peer_id.clone(), ConnectionEvent::Connected);
+        if self.banned_peers.contains(&peer_id) {
+            debug!(self.log, "Dropping connection from banned peer"; "peer_id" => format!("{:?}", peer_id));
+            self.disconnect_peer(peer_id, DisconnectReason::Banned);
+            return false;
+        }
+        if self.draining {
+            debug!(self.log, "Draining, dropping new connection"; "peer_id" => format!("{:?}", peer_id));
+            self.disconnect_peer(peer_id, DisconnectReason::Draining);
+            return false;
+        }
+        let (remote_addr, dialed) = match endpoint {
+            ConnectedPoint::Dialer { address } => (address, true),
+            ConnectedPoint::Listener { send_back_addr, .. } => (send_back_addr, false),
+        };
+        if let Some(subnet) = IpSubnet::from_multiaddr(&remote_addr) {
+            if subnet_full(&self.subnet_peer_counts, self.max_peers_per_subnet, &subnet) {
+                debug!(self.log, "Subnet peer limit reached, dropping connection"; "peer_id" => format!("{:?}", peer_id), "address" => format!("{}", remote_addr));
+                self.disconnect_peer(peer_id, DisconnectReason::SubnetLimit);
+                return false;
+            }
+            *self.subnet_peer_counts.entry(subnet.clone()).or_insert(0) += 1;
+            self.peer_subnets.insert(peer_id.clone(), subnet);
+        }
+        self.reconnect_attempts.remove(&peer_id);
+        // a listener's send back address is usually an ephemeral port that cannot be dialed
+        if dialed {
+            self.peer_addresses.insert(peer_id.clone(), remote_addr);
+        }
+        let now = clock::now();
+        self.peer_info.insert(
+            peer_id.clone(),
+            PeerInfo {
+                connected_at: now,
+                latency: None,
+                last_active: now,
+                tag: None,
+            },
+        );
+        self.connected_peers.insert(peer_id);
+
+        if let Some(strategy) = self.eviction_strategy {
+            if self.connected_peers.len() > self.max_peers {
+                if let Some(peer_id) = self.eviction_candidate(strategy) {
+                    debug!(self.log, "Peer limit reached, evicting peer"; "peer_id" => format!("{:?}", peer_id), "strategy" => format!("{:?}", strategy));
+                    self.disconnect_peer(peer_id, DisconnectReason::Evicted);
+                }
+            }
+        }
+        true
+    }
+
+    /// Whether a connection we dialed to a peer was rejected, forgetting that it was.
+    pub fn take_rejected_dial(&mut self, peer_id: &PeerId) -> bool {
+        self.rejected_dials.remove(peer_id)
+    }
+
+    /// Cleans up after every connection closed at once, such as when the swarm holding them is
+    /// replaced. Returns the peers that were connected.
+    pub fn drop_connections(&mut self, reason: DisconnectReason) -> Vec<PeerId> {
+        let peers: Vec<PeerId> = self.connected_peers.iter().cloned().collect();
+        for peer_id in &peers {
+            self.disconnect_reasons.insert(peer_id.clone(), reason);
+            self.peer_disconnected(peer_id);
+        }
+        peers
+    }
+
+    /// Schedules a re-dial of a peer, unless reconnecting is disabled or the peer has used up
+    /// its attempts.
+    fn schedule_reconnect(&mut self, peer_id: PeerId) {
+        let delay = match self.reconnect_delay {
+            Some(delay) => delay,
+            None => {
+                self.forget_addresses(&peer_id);
+                return;
+            }
+        };
+        let attempts = self.reconnect_attempts.entry(peer_id.clone()).or_insert(0);
+        if *attempts >= self.max_reconnect_attempts {
+            debug!(self.log, "Giving up reconnecting to peer"; "peer_id" => format!("{:?}", peer_id));
+            self.reconnect_attempts.remove(&peer_id);
+            self.forget_addresses(&peer_id);
+            return;
+        }
+        *attempts += 1;
+        debug!(self.log, "Scheduling reconnect"; "peer_id" => format!("{:?}", peer_id), "attempt" => *attempts);
+        self.reconnects.insert(peer_id, delay);
+    }
+
+    /// Stops trying to reconnect to a peer.
+    fn abandon_reconnect(&mut self, peer_id: &PeerId) {
+        self.reconnect_attempts.remove(peer_id);
+        self.forget_addresses(peer_id);
+    }
+
+    /// Drops the addresses remembered for a peer we are no longer connected or reconnecting to.
+    fn forget_addresses(&mut self, peer_id: &PeerId) {
+        self.peer_addresses.remove(peer_id);
+        self.identified_addresses.remove(peer_id);
+        self.migrations.remove(peer_id);
+    }
+
+    /// Returns the peers that answered a dial in place of the peer we expected, as
+    /// `(expected, actual)` pairs, since the last call.
+    pub fn take_peer_id_mismatches(&self) -> Vec<(PeerId, PeerId)> {
+        self.dial_expectations.take_mismatches()
+    }
+
+    /// Returns the connection events recorded since the last call.
+    pub fn take_connection_events(&mut self) -> Vec<ConnectionLogEntry> {
+        std::mem::replace(&mut self.connection_events, Vec::new())
+    }
+
+    /// Records a connection lifecycle event for a peer.
+    fn record_connection_event(&mut self, peer_id: PeerId, event: ConnectionEvent) {
+        self.connection_events.push(ConnectionLogEntry {
+            time: SystemTime::now(),
+            peer_id,
+            event,
+        });
+    }
+
+    /// Returns the peers queued for disconnection, emptying the queue.
+    pub fn take_peers_to_disconnect(&mut self) -> Vec<PeerId> {
+        std::mem::replace(&mut self.peers_to_disconnect, Vec::new())
+    }
+
+    /// Shuts down discv5, dropping its UDP socket so the discovery port is freed immediately
+    /// rather than whenever the behaviour happens to be dropped. No further searches are made
+    /// once closed.
+    pub fn close(&mut self) {
+        if self.discovery.take().is_some() {
+            info!(self.log, "Discovery closed"; "port" => self.udp_port);
+        }
+    }
+
+    /// Restarts closed discovery from the ENR on disk. The routing table starts again from the
+    /// boot nodes.
+    pub fn reopen(&mut self, local_key: &Keypair, config: &NetworkConfig) -> error::Result<()> {
+        if self.discovery.is_none() {
+            self.discovery = Some(start_discv5(local_key, config, &self.log)?);
+            info!(self.log, "Discovery reopened"; "port" => self.udp_port);
+        }
+        Ok(())
+    }
+
     /// Search for new peers using the underlying discovery mechanism.
     fn find_peers(&mut self) {
-        // pick a random NodeId
-        let random_node = NodeId::random();
-        debug!(self.log, "Searching for peers");
-        self.discovery.find_node(random_node);
-
-        // update the time until next discovery
+        // update the time until next discovery, even if discovery has closed, so the elapsed
+        // delay is not polled again straight away
         let delay = {
             if self.past_discovery_delay < MAX_TIME_BETWEEN_PEER_SEARCHES {
                 self.past_discovery_delay *= 2;
@@ -145,7 +782,34 @@ impl<TSubstream> Discovery<TSubstream> {
             }
         };
         self.peer_discovery_delay
-            .reset(Instant::now() + Duration::from_secs(delay));
+            .reset(clock::now() + Duration::from_secs(delay));
+
+        let discovery = match self.discovery.as_mut() {
+            Some(discovery) => discovery,
+            None => return,
+        };
+        // pick a random NodeId
+        let random_node = NodeId::random();
+        debug!(self.log, "Searching for peers");
+        discovery.find_node(random_node);
+    }
+
+    /// Whether searching for new peers is paused.
+    pub fn queries_paused(&self) -> bool {
+        self.queries_paused
+    }
+
+    /// Pauses or resumes searching for new peers, such as while the application is slow to
+    /// consume events. A search is made promptly on resuming.
+    pub fn set_queries_paused(&mut self, paused: bool) {
+        if self.queries_paused == paused {
+            return;
+        }
+        debug!(self.log, "Discovery queries paused"; "paused" => paused);
+        self.queries_paused = paused;
+        if !paused {
+            self.peer_discovery_delay.reset(clock::now());
+        }
     }
 }
 
@@ -153,34 +817,82 @@ impl<TSubstream> Discovery<TSubstream> {
 impl<TSubstream> NetworkBehaviour for Discovery<TSubstream>
 where
     TSubstream: AsyncRead + AsyncWrite,
+    <Discv5<TSubstream> as NetworkBehaviour>::ProtocolsHandler: Default,
 {
     type ProtocolsHandler = <Discv5<TSubstream> as NetworkBehaviour>::ProtocolsHandler;
     type OutEvent = <Discv5<TSubstream> as NetworkBehaviour>::OutEvent;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
-        NetworkBehaviour::new_handler(&mut self.discovery)
+        match self.discovery.as_mut() {
+            Some(discovery) => NetworkBehaviour::new_handler(discovery),
+            // discv5 does not use libp2p connections, so a closed discovery needs no handler
+            None => Default::default(),
+        }
     }
 
     fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        // without addresses, dials to banned peers fail
+        if self.banned_peers.contains(peer_id) {
+            return Vec::new();
+        }
         // Let discovery track possible known peers.
-        self.discovery.addresses_of_peer(peer_id)
+        let mut addresses = match self.discovery.as_mut() {
+            Some(discovery) => discovery.addresses_of_peer(peer_id),
+            None => Vec::new(),
+        };
+        let known = self
+            .peer_addresses
+            .get(peer_id)
+            .into_iter()
+            .chain(self.identified_addresses.get(peer_id).into_iter().flatten());
+        for address in known {
+            if !addresses.contains(address) {
+                addresses.push(address.clone());
+            }
+        }
+        // the swarm asks for addresses when dialing the peer, so expect it at each of them
+        for address in addresses.iter() {
+            self.dial_expectations
+                .expect(address.clone(), peer_id.clone());
+        }
+        addresses
     }
 
-    fn inject_connected(&mut self, peer_id: PeerId, _endpoint: ConnectedPoint) {
-        self.connected_peers.insert(peer_id);
+    fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+        let dialed = match endpoint {
+            ConnectedPoint::Dialer { .. } => true,
+            ConnectedPoint::Listener { .. } => false,
+        };
+        // the RPC behaviour reports every connection we dialed, so rejected ones are remembered
+        // until that report is suppressed
+        if !self.accept_connection(peer_id.clone(), endpoint) && dialed {
+            self.rejected_dials.insert(peer_id);
+        }
     }
 
     fn inject_disconnected(&mut self, peer_id: &PeerId, _endpoint: ConnectedPoint) {
-        self.connected_peers.remove(peer_id);
+        self.peer_disconnected(peer_id);
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.record_connection_event(peer_id.clone(), ConnectionEvent::DialFailed);
+        if self.reconnect_attempts.contains_key(peer_id) {
+            self.schedule_reconnect(peer_id.clone());
+        }
     }
 
     fn inject_replaced(
         &mut self,
-        _peer_id: PeerId,
+        peer_id: PeerId,
         _closed: ConnectedPoint,
-        _opened: ConnectedPoint,
+        opened: ConnectedPoint,
     ) {
-        // discv5 doesn't implement
+        // a migrated connection replaces the old one once established
+        self.migrations.remove(&peer_id);
+        if let ConnectedPoint::Dialer { address } = opened {
+            debug!(self.log, "Connection replaced"; "peer_id" => format!("{:?}", peer_id), "address" => format!("{}", address));
+            self.peer_addresses.insert(peer_id, address);
+        }
     }
 
     fn inject_node_event(
@@ -204,8 +916,16 @@ where
         loop {
             match self.peer_discovery_delay.poll() {
                 Ok(Async::Ready(_)) => {
-                    if self.connected_peers.len() < self.max_peers {
+                    if !self.draining
+                        && !self.queries_paused
+                        && self.connected_peers.len() < self.max_peers
+                    {
                         self.find_peers();
+                    } else {
+                        // check again later rather than polling the elapsed delay
+                        self.peer_discovery_delay.reset(
+                            clock::now() + Duration::from_secs(self.past_discovery_delay),
+                        );
                     }
                 }
                 Ok(Async::NotReady) => break,
@@ -215,20 +935,49 @@ where
             }
         }
 
+        // dial addresses the application asked for
+        if let Some(address) = self.addresses_to_dial.pop_front() {
+            debug!(self.log, "Dialing address"; "address" => format!("{}", address));
+            return Async::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+
+        // re-dial peers that dropped unexpectedly
+        while let Ok(Async::Ready(Some(expired))) = self.reconnects.poll() {
+            let peer_id = expired.into_inner();
+            if self.connected_peers.contains(&peer_id) || self.banned_peers.contains(&peer_id) {
+                continue;
+            }
+            if self.draining || self.connected_peers.len() >= self.max_peers {
+                self.abandon_reconnect(&peer_id);
+                continue;
+            }
+            debug!(self.log, "Reconnecting to peer"; "peer_id" => format!("{:?}", peer_id));
+            return Async::Ready(NetworkBehaviourAction::DialPeer { peer_id });
+        }
+
         // Poll discovery
-        loop {
-            match self.discovery.poll(params) {
+        while let Some(discovery) = self.discovery.as_mut() {
+            match discovery.poll(params) {
                 Async::Ready(NetworkBehaviourAction::GenerateEvent(event)) => {
                     match event {
-                        Discv5Event::Discovered(_enr) => {
+                        Discv5Event::Discovered(enr) => {
                             // not concerned about FINDNODE results, rather the result of an entire
-                            // query.
+                            // query, except to notice connected peers that have moved.
+                            if self.is_oversized(&enr) {
+                                continue;
+                            }
+                            if let (Some(ip), Some(tcp)) = (enr.ip(), enr.tcp()) {
+                                let mut address = Multiaddr::from(ip);
+                                address.push(Protocol::Tcp(tcp));
+                                let peer_id = enr.peer_id();
+                                self.migrate_if_moved(&peer_id, &[address]);
+                            }
                         }
                         Discv5Event::SocketUpdated(socket) => {
                             info!(self.log, "Address updated"; "IP" => format!("{}",socket.ip()));
                             let mut address = Multiaddr::from(socket.ip());
                             address.push(Protocol::Tcp(self.tcp_port));
-                            let enr = self.discovery.local_enr();
+                            let enr = discovery.local_enr();
                             save_enr_to_disc(Path::new(&self.enr_dir), enr, &self.log);
 
                             return Async::Ready(NetworkBehaviourAction::ReportObservedAddr {
@@ -242,9 +991,22 @@ where
                             }
                             for peer_id in closer_peers {
                                 // if we need more peers, attempt a connection
-                                if self.connected_peers.len() < self.max_peers
+                                if !self.draining
+                                    && self.connected_peers.len() < self.max_peers
                                     && self.connected_peers.get(&peer_id).is_none()
+                                    && !self.banned_peers.contains(&peer_id)
+                                    && !self.oversized_enr_peers.contains(&peer_id)
                                 {
+                                    // prefer peers from subnets we are not already saturated with
+                                    let saturated = discovery
+                                        .addresses_of_peer(&peer_id)
+                                        .iter()
+                                        .filter_map(IpSubnet::from_multiaddr)
+                                        .any(|subnet| subnet_full(&self.subnet_peer_counts, self.max_peers_per_subnet, &subnet));
+                                    if saturated {
+                                        debug!(self.log, "Skipping discovered peer, subnet is full"; "peer_id"=> format!("{:?}", peer_id));
+                                        continue;
+                                    }
                                     debug!(self.log, "Peer discovered"; "peer_id"=> format!("{:?}", peer_id));
                                     return Async::Ready(NetworkBehaviourAction::DialPeer {
                                         peer_id,
@@ -264,6 +1026,206 @@ where
     }
 }
 
+/// The peer ids we expect to reach at the addresses we dial. Shared between discovery, which
+/// picks the addresses, and the transport, which learns who answered, so that a peer answering
+/// in place of another can be detected and rejected.
+#[derive(Clone, Default)]
+pub struct DialExpectations {
+    inner: Arc<Mutex<DialExpectationsInner>>,
+}
+
+#[derive(Default)]
+struct DialExpectationsInner {
+    /// The peer expected at each address, and when it was expected.
+    expected: HashMap<Multiaddr, (PeerId, Instant)>,
+    /// Detected mismatches, as `(expected, actual)` pairs.
+    mismatches: Vec<(PeerId, PeerId)>,
+}
+
+impl DialExpectations {
+    /// Records that dialing an address should reach the given peer.
+    pub fn expect(&self, address: Multiaddr, peer_id: PeerId) {
+        let mut inner = self.inner.lock();
+        let now = clock::now();
+        inner
+            .expected
+            .retain(|_, (_, since)| now.duration_since(*since) < DIAL_EXPECTATION_TTL);
+        inner.expected.insert(address, (peer_id, now));
+    }
+
+    /// Checks the peer that answered a dial. Returns the expected peer if a different one
+    /// answered, recording the mismatch.
+    pub fn check(&self, address: &Multiaddr, actual: &PeerId) -> Option<PeerId> {
+        let mut inner = self.inner.lock();
+        let (expected, _) = inner.expected.remove(address)?;
+        if expected == *actual {
+            return None;
+        }
+        inner.mismatches.push((expected.clone(), actual.clone()));
+        Some(expected)
+    }
+
+    /// Returns the mismatches detected since the last call.
+    fn take_mismatches(&self) -> Vec<(PeerId, PeerId)> {
+        std::mem::replace(&mut self.inner.lock().mismatches, Vec::new())
+    }
+}
+
+/// A connection lifecycle event, recorded for post-mortem debugging.
+#[derive(Debug, Clone)]
+pub struct ConnectionLogEntry {
+    /// When the event occurred.
+    pub time: SystemTime,
+    /// The peer the event relates to.
+    pub peer_id: PeerId,
+    /// What happened.
+    pub event: ConnectionEvent,
+}
+
+/// The kinds of connection lifecycle events.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// A connection to the peer was established.
+    Connected,
+    /// The connection to the peer closed.
+    Disconnected(DisconnectReason),
+    /// Dialing the peer failed.
+    DialFailed,
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    /// The connection closed without us asking for it, e.g. the peer went away or timed out.
+    Remote,
+    /// We were draining and not accepting new connections.
+    Draining,
+    /// The peer's subnet already had the maximum number of peers.
+    SubnetLimit,
+    /// We had too many peers and chose this one to disconnect.
+    Evicted,
+    /// The application asked for the peer to be disconnected.
+    Requested,
+    /// The peer is banned.
+    Banned,
+    /// The peer announced a subscription to a topic we do not support.
+    UnsupportedTopic,
+    /// The peer sent no RPC or gossip for longer than the application idle timeout.
+    AppIdle,
+    /// Our network identity was rotated, closing every connection of the old identity.
+    IdentityRotated,
+}
+
+/// What is known of a connected peer.
+struct PeerInfo {
+    /// When the connection was established.
+    connected_at: Instant,
+    /// The last ping round trip time, once measured.
+    latency: Option<Duration>,
+    /// When the peer last sent us something.
+    last_active: Instant,
+    /// The purpose the connection was tagged with, if any.
+    tag: Option<ConnectionTag>,
+}
+
+/// The purpose of a connection, protecting it from eviction. Untagged connections are evicted
+/// first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConnectionTag {
+    /// The peer is being synced from.
+    Sync,
+}
+
+/// The network prefix used to group peers: a /24 for IPv4 or a /48 for IPv6 addresses.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum IpSubnet {
+    V4([u8; 3]),
+    V6([u8; 6]),
+}
+
+impl IpSubnet {
+    fn from_ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                IpSubnet::V4([octets[0], octets[1], octets[2]])
+            }
+            IpAddr::V6(ip) => {
+                let mut prefix = [0; 6];
+                prefix.copy_from_slice(&ip.octets()[..6]);
+                IpSubnet::V6(prefix)
+            }
+        }
+    }
+
+    /// The subnet of the first IP address in a multiaddr, if it has one.
+    fn from_multiaddr(addr: &Multiaddr) -> Option<Self> {
+        addr.iter().find_map(|protocol| match protocol {
+            Protocol::Ip4(ip) => Some(IpSubnet::from_ip(IpAddr::V4(ip))),
+            Protocol::Ip6(ip) => Some(IpSubnet::from_ip(IpAddr::V6(ip))),
+            _ => None,
+        })
+    }
+}
+
+/// The index of the routing table bucket a node falls in: its log2 XOR distance from the local
+/// node, less one. The local node has no bucket.
+fn bucket_index(local_id: &[u8; 32], node_id: &[u8; 32]) -> Option<usize> {
+    local_id
+        .iter()
+        .zip(node_id.iter())
+        .enumerate()
+        .find_map(|(byte, (local, node))| {
+            let distance = local ^ node;
+            if distance == 0 {
+                None
+            } else {
+                Some(255 - (byte * 8 + distance.leading_zeros() as usize))
+            }
+        })
+}
+
+/// Whether an address advertised by a peer is safe to dial. Only addresses starting with a
+/// routable IP are accepted. Private and link-local ranges are accepted only if allowed.
+fn dialable_address(addr: &Multiaddr, allow_private: bool) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => {
+            if ip.is_loopback() || ip.is_unspecified() || ip.is_broadcast() {
+                return false;
+            }
+            allow_private || !(ip.is_private() || ip.is_link_local())
+        }
+        Some(Protocol::Ip6(ip)) => {
+            if ip.is_loopback() || ip.is_unspecified() {
+                return false;
+            }
+            // unique local (fc00::/7) and link-local (fe80::/10) ranges
+            let first_segment = ip.segments()[0];
+            let private =
+                (first_segment & 0xfe00) == 0xfc00 || (first_segment & 0xffc0) == 0xfe80;
+            allow_private || !private
+        }
+        _ => false,
+    }
+}
+
+/// The size of an ENR's RLP encoding, in bytes.
+fn enr_size(enr: &Enr) -> usize {
+    rlp::encode(enr).len()
+}
+
+/// Whether a subnet already has the maximum number of connected peers.
+fn subnet_full(
+    counts: &HashMap<IpSubnet, usize>,
+    max_peers_per_subnet: Option<usize>,
+    subnet: &IpSubnet,
+) -> bool {
+    match max_peers_per_subnet {
+        Some(max) => counts.get(subnet).cloned().unwrap_or(0) >= max,
+        None => false,
+    }
+}
+
 /// Loads an ENR from file if it exists and matches the current NodeId and sequence number. If none
 /// exists, generates a new one.
 ///
@@ -323,6 +1285,64 @@ fn load_enr(
     Ok(local_enr)
 }
 
+/// Starts discv5 with the local ENR, advertising the configured application fields and seeding
+/// the routing table with the boot nodes.
+fn start_discv5<TSubstream>(
+    local_key: &Keypair,
+    config: &NetworkConfig,
+    log: &slog::Logger,
+) -> error::Result<Discv5<TSubstream>> {
+    // checks if current ENR matches that found on disk
+    let local_enr = load_enr(local_key, config, log)?;
+
+    info!(log, "ENR Initialised"; "ENR" => local_enr.to_base64(), "Seq" => local_enr.seq());
+    debug!(log, "Discv5 Node ID Initialised"; "node_id" => format!("{}",local_enr.node_id()));
+
+    let mut discovery = Discv5::new(local_enr, local_key.clone(), config.listen_address)
+        .map_err(|e| format!("Discv5 service failed. Error: {:?}", e))?;
+
+    // Advertise application fields, leaving the ENR untouched if they are already set so the
+    // sequence number is not bumped on every restart
+    for (key, value) in config.custom_enr_fields.iter() {
+        if RESERVED_ENR_KEYS.contains(&key.as_str()) {
+            return Err(format!("Reserved ENR key cannot be set: {}", key).into());
+        }
+        if discovery.local_enr().get(key) != Some(value) {
+            discovery
+                .enr_insert(key, value.clone())
+                .map_err(|e| format!("Could not set ENR field {}: {:?}", key, e))?;
+        }
+    }
+    if !config.custom_enr_fields.is_empty() {
+        save_enr_to_disc(&config.network_dir, discovery.local_enr(), log);
+    }
+
+    // Add bootnodes to routing table
+    for bootnode_enr in config.boot_nodes.clone() {
+        if enr_size(&bootnode_enr) > config.max_enr_size {
+            warn!(log, "Rejecting oversized boot node ENR"; "node_id" => format!("{}", bootnode_enr.node_id()), "size" => enr_size(&bootnode_enr), "max" => config.max_enr_size);
+            continue;
+        }
+        debug!(
+            log,
+            "Adding node to routing table";
+            "Node ID" => format!("{}",
+            bootnode_enr.node_id())
+        );
+        discovery.add_enr(bootnode_enr);
+    }
+
+    Ok(discovery)
+}
+
+/// Reads the attnets bitfield from its ENR field, as written by `update_attnets`.
+fn decode_attnets(field: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    let len = field.len().min(bytes.len());
+    bytes[..len].copy_from_slice(&field[..len]);
+    u64::from_le_bytes(bytes)
+}
+
 fn save_enr_to_disc(dir: &Path, enr: &Enr, log: &slog::Logger) {
     let _ = std::fs::create_dir_all(dir);
     match File::create(dir.join(Path::new(ENR_FILENAME)))
@@ -338,4 +1358,178 @@ fn save_enr_to_disc(dir: &Path, enr: &Enr, log: &slog::Logger) {
             );
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::test_utils::*;
+
+    fn address(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn dial_expectation_mismatch_is_reported() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let expectations = DialExpectations::default();
+            let expected = PeerId::random();
+            let actual = PeerId::random();
+            expectations.expect(address(9000), expected.clone());
+            assert_eq!(expectations.check(&address(9000), &actual), Some(expected.clone()));
+            assert_eq!(expectations.take_mismatches(), vec![(expected, actual)]);
+        });
+    }
+
+    #[test]
+    fn dial_expectations_time_out() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let expectations = DialExpectations::default();
+            expectations.expect(address(9000), PeerId::random());
+            clock.advance(DIAL_EXPECTATION_TTL - Duration::from_secs(1));
+            expectations.expect(address(9001), PeerId::random());
+            clock.advance(Duration::from_secs(1));
+            // expired expectations are dropped as new ones are recorded
+            expectations.expect(address(9002), PeerId::random());
+            assert_eq!(expectations.check(&address(9000), &PeerId::random()), None);
+            assert!(expectations.check(&address(9001), &PeerId::random()).is_some());
+        });
+    }
+
+    fn test_discovery(key: &Keypair, config: &NetworkConfig) -> Discovery<TestSubstream> {
+        Discovery::new(key, config, DialExpectations::default(), &null_logger())
+            .expect("discovery starts")
+    }
+
+    #[test]
+    fn attnets_survive_a_restart() {
+        let key = Keypair::generate_secp256k1();
+        let config = test_config();
+        let mut discovery = test_discovery(&key, &config);
+        discovery.update_attnets(3, true).unwrap();
+        discovery.close();
+
+        // the restarted node loads its ENR from disk and adds to the subnets it advertised
+        let mut discovery = test_discovery(&key, &config);
+        discovery.update_attnets(5, true).unwrap();
+        let field = discovery
+            .local_enr()
+            .and_then(|enr| enr.get(ATTNETS_ENR_KEY).cloned())
+            .expect("attnets advertised");
+        assert_eq!(decode_attnets(&field), (1 << 3) | (1 << 5));
+    }
+
+    /// Connects `count` new peers one second apart, returning them in connection order.
+    fn connect_peers(
+        discovery: &mut Discovery<TestSubstream>,
+        clock: &MockClock,
+        count: u16,
+    ) -> Vec<PeerId> {
+        (0..count)
+            .map(|port| {
+                let peer_id = PeerId::random();
+                clock.advance(Duration::from_secs(1));
+                discovery.inject_connected(
+                    peer_id.clone(),
+                    ConnectedPoint::Dialer {
+                        address: address(9000 + port),
+                    },
+                );
+                peer_id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn peers_over_the_limit_are_kept_without_an_eviction_strategy() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut config = test_config();
+            config.max_peers = 2;
+            let mut discovery = test_discovery(&Keypair::generate_secp256k1(), &config);
+            connect_peers(&mut discovery, &clock, 3);
+            assert!(discovery.take_peers_to_disconnect().is_empty());
+            assert_eq!(discovery.connected_peer_set().len(), 3);
+        });
+    }
+
+    #[test]
+    fn eviction_strategy_chooses_the_peer_to_disconnect() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut config = test_config();
+            config.max_peers = 2;
+            config.eviction_strategy = Some(EvictionStrategy::Newest);
+            let mut discovery = test_discovery(&Keypair::generate_secp256k1(), &config);
+            let peers = connect_peers(&mut discovery, &clock, 3);
+            assert_eq!(discovery.take_peers_to_disconnect(), vec![peers[2].clone()]);
+
+            config.eviction_strategy = Some(EvictionStrategy::MostIdle);
+            discovery.close();
+            let mut discovery = test_discovery(&Keypair::generate_secp256k1(), &config);
+            let peers = connect_peers(&mut discovery, &clock, 2);
+            clock.advance(Duration::from_secs(1));
+            discovery.record_activity(&peers[0]);
+            connect_peers(&mut discovery, &clock, 1);
+            // the second peer has been quiet since it connected, before the others were heard from
+            assert_eq!(discovery.take_peers_to_disconnect(), vec![peers[1].clone()]);
+        });
+    }
+
+    fn test_enr(port: u16, custom_field: Option<Vec<u8>>) -> Enr {
+        let mut builder = EnrBuilder::new();
+        builder
+            .ip("127.0.0.1".parse().unwrap())
+            .tcp(port)
+            .udp(port);
+        if let Some(value) = custom_field {
+            builder.add_value("junk", value);
+        }
+        builder.build(&Keypair::generate_secp256k1()).unwrap()
+    }
+
+    #[test]
+    fn oversized_enrs_are_not_added_to_the_routing_table() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut config = test_config();
+            config.max_enr_size = 200;
+            let mut discovery = test_discovery(&Keypair::generate_secp256k1(), &config);
+            let normal = test_enr(9000, None);
+            let oversized = test_enr(9001, Some(vec![0; 200]));
+            assert!(enr_size(&normal) <= config.max_enr_size);
+            assert!(enr_size(&oversized) > config.max_enr_size);
+
+            discovery.add_enr(oversized);
+            assert_eq!(discovery.known_peer_count(), 0);
+            discovery.add_enr(normal);
+            assert_eq!(discovery.known_peer_count(), 1);
+        });
+    }
+
+    #[test]
+    fn app_idle_peers_are_disconnected_unless_tagged_for_sync() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut discovery = test_discovery(&Keypair::generate_secp256k1(), &test_config());
+            let peers = connect_peers(&mut discovery, &clock, 3);
+            let (idle, syncing, busy) = (&peers[0], &peers[1], &peers[2]);
+            discovery.tag_connection(syncing, ConnectionTag::Sync);
+
+            let timeout = Duration::from_secs(60);
+            clock.advance(timeout);
+            discovery.record_activity(busy);
+            let next_idle = discovery.disconnect_app_idle_peers(timeout);
+
+            assert_eq!(discovery.take_peers_to_disconnect(), vec![idle.clone()]);
+            assert_eq!(
+                discovery.disconnect_reasons.get(idle),
+                Some(&DisconnectReason::AppIdle)
+            );
+            assert_eq!(next_idle, Some(clock::now() + timeout));
+        });
+    }
+}
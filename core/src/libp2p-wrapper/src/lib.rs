@@ -3,15 +3,23 @@
 ///
 /// This crate builds and manages the libp2p services required by the beacon node.
 pub mod behaviour;
+mod chunking;
+mod clock;
 mod config;
 mod discovery;
+mod peer_selector;
 pub mod error;
 pub mod rpc;
 mod service;
+mod snapshot;
+#[cfg(test)]
+mod test_utils;
 
-pub use behaviour::PubsubMessage;
+pub use behaviour::{
+    DropReason, EventFilter, GossipState, MultiplexerKind, PublishError, PubsubMessage,
+};
 pub use config::{
-    Config as NetworkConfig, BEACON_ATTESTATION_TOPIC, BEACON_BLOCK_TOPIC, SHARD_TOPIC_PREFIX,TOPIC_ENCODING_POSTFIX, TOPIC_PREFIX,
+    Config as NetworkConfig, ConsumerGonePolicy, EvictionStrategy, PeerAuthorizer, ServingBudget, TransportBuffers, BEACON_ATTESTATION_TOPIC, PROBE_TOPIC, BEACON_BLOCK_TOPIC, SHARD_TOPIC_PREFIX,TOPIC_ENCODING_POSTFIX, TOPIC_PREFIX,
 };
 pub use libp2p::gossipsub::{Topic, TopicHash};
 pub use libp2p::multiaddr;
@@ -20,10 +28,15 @@ pub use libp2p::{
     gossipsub::{GossipsubConfig, GossipsubConfigBuilder},
     PeerId,
 };
+pub use discovery::{ConnectionEvent, ConnectionTag, ConnectionLogEntry, DisconnectReason};
+pub use peer_selector::{MostRecentlyActive, PeerCandidate, PeerSelector};
 pub use rpc::{RPCEvent,RPCRequest,RPCResponse,RPCErrorResponse,RPCProtocol,RPC};
+pub use clock::{Clock, MockClock};
 pub use service::Libp2pEvent;
 pub use service::Service;
+pub use snapshot::{NetworkDiff, NetworkSnapshot, TopologyEdge, TopologyEdgeKind, TopologyGraph};
 pub use service::Message;
 pub use service::DISCOVERY;
 pub use service::GOSSIP;
 pub use service::RPC;
+pub use service::VALIDATION;
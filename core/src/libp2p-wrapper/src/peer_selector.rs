@@ -0,0 +1,42 @@
+//! Chooses which connected peers to use when the application asks for a sample of them.
+
+use crate::discovery::ConnectionTag;
+use libp2p::PeerId;
+use std::time::{Duration, Instant};
+
+/// What is known of a connected peer when choosing between peers.
+#[derive(Clone, Debug)]
+pub struct PeerCandidate {
+    pub peer_id: PeerId,
+    /// When the connection was established.
+    pub connected_at: Instant,
+    /// The last ping round trip time, once measured.
+    pub latency: Option<Duration>,
+    /// When the peer last sent us something.
+    pub last_active: Instant,
+    /// The purpose the connection was tagged with, if any.
+    pub tag: Option<ConnectionTag>,
+    /// The progress marker the application last noted for the peer, if any.
+    pub progress: Option<u64>,
+}
+
+/// Chooses peers from the connected candidates.
+pub trait PeerSelector: Send {
+    /// Chooses up to `count` of the candidates, most preferred first.
+    fn select(&self, candidates: Vec<PeerCandidate>, count: usize) -> Vec<PeerId>;
+}
+
+/// Prefers the peers that have sent us something most recently.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MostRecentlyActive;
+
+impl PeerSelector for MostRecentlyActive {
+    fn select(&self, mut candidates: Vec<PeerCandidate>, count: usize) -> Vec<PeerId> {
+        candidates.sort_by(|a, b| b.last_active.cmp(&a.last_active));
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|candidate| candidate.peer_id)
+            .collect()
+    }
+}
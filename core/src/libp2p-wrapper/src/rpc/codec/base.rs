@@ -52,6 +52,11 @@ where
             response_code: None,
         }
     }
+
+    /// Whether part of a response has been read, i.e. its response code.
+    pub fn response_started(&self) -> bool {
+        self.response_code.is_some()
+    }
 }
 
 impl<TCodec> Encoder for BaseInboundCodec<TCodec>
@@ -118,7 +123,7 @@ where
             }
         };
 
-        if RPCErrorResponse::is_response(response_code) {
+        let decoded = if RPCErrorResponse::is_response(response_code) {
             // decode an actual response
             self.inner
                 .decode(src)
@@ -128,6 +133,11 @@ where
             self.inner
                 .decode_error(src)
                 .map(|r| r.map(|resp| RPCErrorResponse::from_error(response_code, resp)))
+        };
+        // the next response starts with its own response code
+        if let Ok(Some(_)) = decoded {
+            self.response_code = None;
         }
+        decoded
     }
 }
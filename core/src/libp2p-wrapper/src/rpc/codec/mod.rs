@@ -59,4 +59,83 @@ impl Decoder for OutboundCodec {
             OutboundCodec::Serenity(codec) => codec.decode(src),
         }
     }
+
+    /// A stream that closes part way through a response fails with `IncompleteResponse`, rather
+    /// than the generic error for bytes left on the stream.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            OutboundCodec::Serenity(codec) => {
+                if src.is_empty() && !codec.response_started() {
+                    return Ok(None);
+                }
+                match codec.decode(src)? {
+                    Some(response) => Ok(Some(response)),
+                    None => Err(RPCError::IncompleteResponse),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::protocol::ProtocolId;
+    use crate::rpc::{ErrorMessage, RPCResponse};
+
+    const MAX_PACKET_SIZE: usize = 1024;
+
+    fn codecs(message_name: &str) -> (InboundCodec, OutboundCodec) {
+        let protocol = || ProtocolId::new(message_name, "1", "ssz");
+        (
+            InboundCodec::Serenity(BaseInboundCodec::new(SerenityInboundCodec::new(
+                protocol(),
+                MAX_PACKET_SIZE,
+            ))),
+            OutboundCodec::Serenity(BaseOutboundCodec::new(SerenityOutboundCodec::new(
+                protocol(),
+                MAX_PACKET_SIZE,
+            ))),
+        )
+    }
+
+    /// Encodes a response as the responding peer would and decodes it as the requester would.
+    fn round_trip(message_name: &str, response: RPCErrorResponse) -> Option<RPCErrorResponse> {
+        let (mut inbound, mut outbound) = codecs(message_name);
+        let mut bytes = BytesMut::new();
+        inbound.encode(response, &mut bytes).unwrap();
+        outbound.decode(&mut bytes).unwrap()
+    }
+
+    #[test]
+    fn successful_responses_round_trip() {
+        match round_trip(
+            "sample_recent",
+            RPCErrorResponse::Success(RPCResponse::Message(b"item".to_vec())),
+        ) {
+            Some(RPCErrorResponse::Success(RPCResponse::Message(item))) => {
+                assert_eq!(item, b"item".to_vec())
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_responses_round_trip() {
+        let error = ErrorMessage {
+            error_message: b"No recent item".to_vec(),
+        };
+        match round_trip("sample_recent", RPCErrorResponse::ServerError(error.clone())) {
+            Some(RPCErrorResponse::ServerError(decoded)) => {
+                assert_eq!(decoded.error_message, error.error_message)
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        match round_trip("hello", RPCErrorResponse::InvalidRequest(error.clone())) {
+            Some(RPCErrorResponse::InvalidRequest(decoded)) => {
+                assert_eq!(decoded.as_string(), "No recent item")
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
 }
@@ -69,6 +69,10 @@ impl Decoder for SerenityInboundCodec {
                     "1" => Ok(Some(RPCRequest::Message(packet.to_vec()))),
                     _ => Err(RPCError::InvalidProtocol("Unknown Message version")),
                 }
+                "sample_recent" => match self.protocol.version.as_str() {
+                    "1" => Ok(Some(RPCRequest::SampleRecent)),
+                    _ => Err(RPCError::InvalidProtocol("Unknown SampleRecent version")),
+                },
                 _ => Err(RPCError::InvalidProtocol("Unknown message name.")),
             },
             Ok(None) => Ok(None),
@@ -107,6 +111,7 @@ impl Encoder for SerenityOutboundCodec {
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let bytes = match item {
             RPCRequest::Message(req) => Bytes::from(req),
+            RPCRequest::SampleRecent => Bytes::new(),
         };
         // length-prefix
         self.inner
@@ -123,7 +128,7 @@ impl Decoder for SerenityOutboundCodec {
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match self.inner.decode(src).map_err(RPCError::from) {
             Ok(Some(packet)) => match self.protocol.message_name.as_str() {
-                "hello" => match self.protocol.version.as_str() {
+                "hello" | "sample_recent" => match self.protocol.version.as_str() {
                     
                     "1" => Ok(Some(RPCResponse::Message(packet.to_vec()))),
                     _ => Err(RPCError::InvalidProtocol("Unknown rpc message version.")),
@@ -141,7 +146,9 @@ impl OutboundCodec for SerenityOutboundCodec {
 
     fn decode_error(&mut self, src: &mut BytesMut) -> Result<Option<Self::ErrorType>, RPCError> {
         match self.inner.decode(src).map_err(RPCError::from) {
-            Ok(Some(_packet)) => Ok(None),
+            Ok(Some(packet)) => Ok(Some(ErrorMessage {
+                error_message: packet.to_vec(),
+            })),
             Ok(None) => Ok(None),
             Err(e) => Err(e),
         }
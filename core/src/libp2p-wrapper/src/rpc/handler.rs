@@ -1,21 +1,34 @@
 use super::methods::{RPCErrorResponse, RequestId};
 use super::protocol::{RPCError, RPCProtocol, RPCRequest};
 use super::RPCEvent;
+use crate::clock;
 use crate::rpc::protocol::{InboundFramed, OutboundFramed};
 use core::marker::PhantomData;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::prelude::*;
 use libp2p::core::upgrade::{InboundUpgrade, OutboundUpgrade};
 use libp2p::swarm::protocols_handler::{
     KeepAlive, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr, SubstreamProtocol,
 };
 use smallvec::SmallVec;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 /// The time (in seconds) before a substream that is awaiting a response times out.
 pub const RESPONSE_TIMEOUT: u64 = 10;
 
+/// What the RPC handler reports to the RPC behaviour.
+#[derive(Debug)]
+pub enum HandlerEvent {
+    /// A request received, or a response or error for a request we sent.
+    RPC(RPCEvent),
+    /// An inbound request was refused because the open substream limit was reached.
+    InboundRefused(RPCRequest),
+}
+
 /// Implementation of `ProtocolsHandler` for the RPC protocol.
 pub struct RPCHandler<TSubstream>
 where
@@ -28,7 +41,7 @@ where
     pending_error: Option<ProtocolsHandlerUpgrErr<RPCError>>,
 
     /// Queue of events to produce in `poll()`.
-    events_out: SmallVec<[RPCEvent; 4]>,
+    events_out: SmallVec<[HandlerEvent; 4]>,
 
     /// Queue of outbound substreams to open.
     dial_queue: SmallVec<[RPCEvent; 4]>,
@@ -36,6 +49,9 @@ where
     /// Current number of concurrent outbound substreams being opened.
     dial_negotiated: u32,
 
+    /// Requests cancelled while their substream was being opened, to be dropped once open.
+    cancelled_requests: FnvHashSet<RequestId>,
+
     /// Map of current substreams awaiting a response to an RPC request.
     waiting_substreams: FnvHashMap<RequestId, WaitingResponse<TSubstream>>,
 
@@ -54,6 +70,18 @@ where
     /// After the given duration has elapsed, an inactive connection will shutdown.
     inactive_timeout: Duration,
 
+    /// Number of substreams open across all handlers.
+    open_substreams: Arc<AtomicUsize>,
+
+    /// This handler's contribution to `open_substreams` when it was last updated.
+    reported_substreams: usize,
+
+    /// The limit on `open_substreams`, if any.
+    max_open_substreams: Option<usize>,
+
+    /// Response timeouts for protocols that do not use `RESPONSE_TIMEOUT`, by message name.
+    response_timeouts: Arc<HashMap<String, Duration>>,
+
     /// Marker to pin the generic stream.
     _phantom: PhantomData<TSubstream>,
 }
@@ -95,6 +123,9 @@ where
     pub fn new(
         listen_protocol: SubstreamProtocol<RPCProtocol>,
         inactive_timeout: Duration,
+        open_substreams: Arc<AtomicUsize>,
+        max_open_substreams: Option<usize>,
+        response_timeouts: Arc<HashMap<String, Duration>>,
     ) -> Self {
         RPCHandler {
             listen_protocol,
@@ -102,12 +133,17 @@ where
             events_out: SmallVec::new(),
             dial_queue: SmallVec::new(),
             dial_negotiated: 0,
+            cancelled_requests: FnvHashSet::default(),
             waiting_substreams: FnvHashMap::default(),
             substreams: Vec::new(),
             current_substream_id: 1,
             max_dial_negotiated: 8,
             keep_alive: KeepAlive::Yes,
             inactive_timeout,
+            open_substreams,
+            reported_substreams: 0,
+            max_open_substreams,
+            response_timeouts,
             _phantom: PhantomData,
         }
     }
@@ -133,12 +169,81 @@ where
         &mut self.listen_protocol
     }
 
-    /// Opens an outbound substream with a request.
+    /// Opens an outbound substream with a request, unless the limit on open substreams has been
+    /// reached, in which case the request fails.
     #[inline]
     pub fn send_request(&mut self, rpc_event: RPCEvent) {
+        if let Some(max) = self.at_substream_limit() {
+            self.events_out.push(HandlerEvent::RPC(RPCEvent::Error(
+                rpc_event.id(),
+                RPCError::Custom(format!("Open substream limit of {} reached", max)),
+            )));
+            return;
+        }
         self.keep_alive = KeepAlive::Yes;
 
         self.dial_queue.push(rpc_event);
+        self.update_open_substreams();
+    }
+
+    /// Drops an outbound request, closing its substream if it has one.
+    fn cancel_request(&mut self, id: RequestId) {
+        let queued = self.dial_queue.len();
+        self.dial_queue.retain(|event| event.id() != id);
+        if self.dial_queue.len() != queued {
+            return;
+        }
+        let open = self.substreams.len();
+        self.substreams.retain(|substream| match substream {
+            SubstreamState::RequestPendingResponse { rpc_event, .. } => rpc_event.id() != id,
+            SubstreamState::ResponsePendingSend { .. } => true,
+        });
+        if self.substreams.len() == open && self.dial_negotiated > 0 {
+            self.cancelled_requests.insert(id);
+        }
+    }
+
+    /// How long a response to the request may take.
+    fn response_timeout(&self, request: &RPCRequest) -> Duration {
+        self.response_timeouts
+            .get(request.message_name())
+            .cloned()
+            .unwrap_or_else(|| Duration::from_secs(RESPONSE_TIMEOUT))
+    }
+
+    /// Returns the limit if the number of open substreams has reached it.
+    fn at_substream_limit(&self) -> Option<usize> {
+        self.max_open_substreams
+            .filter(|max| self.open_substreams.load(Ordering::SeqCst) >= *max)
+    }
+
+    /// Brings this handler's contribution to the shared substream count up to date. Queued
+    /// requests count as open, so that requests made in quick succession cannot overshoot the
+    /// limit.
+    fn update_open_substreams(&mut self) {
+        let open = self.dial_queue.len()
+            + self.dial_negotiated as usize
+            + self.waiting_substreams.len()
+            + self.substreams.len();
+        if open > self.reported_substreams {
+            self.open_substreams
+                .fetch_add(open - self.reported_substreams, Ordering::SeqCst);
+        } else {
+            self.open_substreams
+                .fetch_sub(self.reported_substreams - open, Ordering::SeqCst);
+        }
+        self.reported_substreams = open;
+    }
+}
+
+impl<TSubstream> Drop for RPCHandler<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    fn drop(&mut self) {
+        // the handler's substreams close with it
+        self.open_substreams
+            .fetch_sub(self.reported_substreams, Ordering::SeqCst);
     }
 }
 
@@ -147,7 +252,13 @@ where
     TSubstream: AsyncRead + AsyncWrite,
 {
     fn default() -> Self {
-        RPCHandler::new(SubstreamProtocol::new(RPCProtocol), Duration::from_secs(30))
+        RPCHandler::new(
+            SubstreamProtocol::new(RPCProtocol),
+            Duration::from_secs(30),
+            Arc::new(AtomicUsize::new(0)),
+            None,
+            Arc::new(HashMap::new()),
+        )
     }
 }
 
@@ -156,7 +267,7 @@ where
     TSubstream: AsyncRead + AsyncWrite,
 {
     type InEvent = RPCEvent;
-    type OutEvent = RPCEvent;
+    type OutEvent = HandlerEvent;
     type Error = ProtocolsHandlerUpgrErr<RPCError>;
     type Substream = TSubstream;
     type InboundProtocol = RPCProtocol;
@@ -175,17 +286,27 @@ where
     ) {
         let (req, substream) = out;
 
+        // dropping the substream resets it, failing the remote's request. The refusal is reported
+        // so the application can tell that peers are being turned away.
+        if self.at_substream_limit().is_some() {
+            self.events_out.push(HandlerEvent::InboundRefused(req));
+            return;
+        }
+
         // New inbound request. Store the stream and tag the output.
         let awaiting_stream = WaitingResponse {
             substream,
-            timeout: Instant::now() + Duration::from_secs(RESPONSE_TIMEOUT),
+            timeout: clock::now() + self.response_timeout(&req),
         };
         self.waiting_substreams
             .insert(self.current_substream_id, awaiting_stream);
 
-        self.events_out
-            .push(RPCEvent::Request(self.current_substream_id, req));
+        self.events_out.push(HandlerEvent::RPC(RPCEvent::Request(
+            self.current_substream_id,
+            req,
+        )));
         self.current_substream_id += 1;
+        self.update_open_substreams();
     }
 
     #[inline]
@@ -195,27 +316,33 @@ where
         rpc_event: Self::OutboundOpenInfo,
     ) {
         self.dial_negotiated -= 1;
+        let cancelled = self.cancelled_requests.remove(&rpc_event.id());
+        if self.dial_negotiated == 0 {
+            self.cancelled_requests.clear();
+        }
 
         if self.dial_negotiated == 0
             && self.dial_queue.is_empty()
             && self.waiting_substreams.is_empty()
         {
-            self.keep_alive = KeepAlive::Until(Instant::now() + self.inactive_timeout);
+            self.keep_alive = KeepAlive::Until(clock::now() + self.inactive_timeout);
         } else {
             self.keep_alive = KeepAlive::Yes;
         }
         // add the stream to substreams if we expect a response, otherwise drop the stream.
         if let RPCEvent::Request(id, req) = rpc_event {
-            if req.expect_response() {
+            if req.expect_response() && !cancelled {
+                let timeout = clock::now() + self.response_timeout(&req);
                 let awaiting_stream = SubstreamState::RequestPendingResponse {
                     substream: out,
                     rpc_event: RPCEvent::Request(id, req),
-                    timeout: Instant::now() + Duration::from_secs(RESPONSE_TIMEOUT),
+                    timeout,
                 };
 
                 self.substreams.push(awaiting_stream);
             }
         }
+        self.update_open_substreams();
     }
 
     // Note: If the substream has closed due to inactivity, or the substream is in the
@@ -233,7 +360,7 @@ where
                     });
                 }
             }
-            RPCEvent::Error(_, _) => { std::println!("handler.rs ERROR")}
+            RPCEvent::Error(rpc_id, _) => self.cancel_request(rpc_id),
         }
     }
 
@@ -260,6 +387,23 @@ where
     ) -> Poll<
         ProtocolsHandlerEvent<Self::OutboundProtocol, Self::OutboundOpenInfo, Self::OutEvent>,
         Self::Error,
+    > {
+        let result = self.poll_substreams();
+        self.update_open_substreams();
+        result
+    }
+}
+
+impl<TSubstream> RPCHandler<TSubstream>
+where
+    TSubstream: AsyncRead + AsyncWrite,
+{
+    /// Drives the handler's substreams, producing the handler's next event.
+    fn poll_substreams(
+        &mut self,
+    ) -> Poll<
+        ProtocolsHandlerEvent<RPCRequest, RPCEvent, RPCEvent>,
+        ProtocolsHandlerUpgrErr<RPCError>,
     > {
         if let Some(err) = self.pending_error.take() {
             // Returning an error here will result in dropping any peer that doesn't support any of
@@ -280,7 +424,7 @@ where
 
         // remove any streams that have expired
         self.waiting_substreams
-            .retain(|_k, waiting_stream| Instant::now() <= waiting_stream.timeout);
+            .retain(|_k, waiting_stream| clock::now() <= waiting_stream.timeout);
 
         // drive streams that need to be processed
         for n in (0..self.substreams.len()).rev() {
@@ -295,7 +439,7 @@ where
                         }
                         Err(e) => {
                             return Ok(Async::Ready(ProtocolsHandlerEvent::Custom(
-                                RPCEvent::Error(0, e),
+                                HandlerEvent::RPC(RPCEvent::Error(0, e)),
                             )))
                         }
                     }
@@ -308,20 +452,20 @@ where
                     Ok(Async::Ready(response)) => {
                         if let Some(response) = response {
                                   return Ok(Async::Ready(ProtocolsHandlerEvent::Custom(
-                                RPCEvent::Response(rpc_event.id(), response),
+                                HandlerEvent::RPC(RPCEvent::Response(rpc_event.id(), response)),
                             )));
                         } else {
                             // stream closed early
                             return Ok(Async::Ready(ProtocolsHandlerEvent::Custom(
-                                RPCEvent::Error(
+                                HandlerEvent::RPC(RPCEvent::Error(
                                     rpc_event.id(),
                                     RPCError::Custom("Stream Closed Early".into()),
-                                ),
+                                )),
                             )));
                         }
                     }
                     Ok(Async::NotReady) => {
-                        if Instant::now() < timeout {
+                        if clock::now() < timeout {
                             self.substreams
                                 .push(SubstreamState::RequestPendingResponse {
                                     substream,
@@ -332,7 +476,7 @@ where
                     }
                     Err(e) => {
                         return Ok(Async::Ready(ProtocolsHandlerEvent::Custom(
-                            RPCEvent::Error(rpc_event.id(), e),
+                            HandlerEvent::RPC(RPCEvent::Error(rpc_event.id(), e)),
                         )))
                     }
                 },
@@ -359,3 +503,50 @@ where
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::rpc::{RPCEvent, RPCRequest};
+    use crate::service::Libp2pEvent;
+    use crate::test_utils::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn inbound_requests_over_the_substream_limit_are_reported() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut server_config = test_config();
+        server_config.max_open_substreams = Some(0);
+        let mut client_config = test_config();
+        client_config.libp2p_nodes = vec![listen_addr(&server_config)];
+        let (mut server, _server_rx) = test_service(server_config);
+        let (mut client, _client_rx) = test_service(client_config);
+        let server_id = server.local_peer_id().clone();
+        let client_id = client.local_peer_id().clone();
+        poll_until(&mut runtime, &mut [&mut server, &mut client], |services, _| {
+            services[1].swarm.connected_peers().contains(&server_id)
+        });
+
+        client.swarm.send_rpc(
+            server_id,
+            RPCEvent::Request(1, RPCRequest::Message(b"request".to_vec())),
+        );
+        let events = poll_until(&mut runtime, &mut [&mut server, &mut client], |_, events| {
+            events.iter().any(|(index, event)| match event {
+                Libp2pEvent::InboundRefused { .. } => *index == 0,
+                _ => false,
+            })
+        });
+        for (index, event) in events.iter().filter(|(index, _)| *index == 0) {
+            match event {
+                Libp2pEvent::InboundRefused { peer_id, request } => {
+                    assert_eq!(peer_id, &client_id);
+                    assert_eq!(request, &RPCRequest::Message(b"request".to_vec()));
+                }
+                Libp2pEvent::RPC(_, rpc_event) => {
+                    panic!("server {} saw unexpected RPC event {:?}", index, rpc_event)
+                }
+                _ => {}
+            }
+        }
+    }
+}
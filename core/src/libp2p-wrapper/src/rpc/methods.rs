@@ -10,7 +10,7 @@ pub enum RPCResponse {
     Message(Vec<u8>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RPCErrorResponse {
     Success(RPCResponse),
     InvalidRequest(ErrorMessage),
@@ -48,7 +48,7 @@ impl RPCErrorResponse {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ErrorMessage {
     /// The UTF-8 encoded Error message string.
     pub error_message: Vec<u8>,
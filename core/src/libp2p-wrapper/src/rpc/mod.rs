@@ -4,24 +4,44 @@
 //! direct peer-to-peer communication primarily for sending/receiving chain information for
 //! syncing.
 
+use crate::clock;
+use crate::config::ServingBudget;
 use futures::prelude::*;
-use handler::RPCHandler;
+use handler::{HandlerEvent, RPCHandler};
 use libp2p::core::ConnectedPoint;
 use libp2p::swarm::{
-    protocols_handler::ProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+    protocols_handler::{ProtocolsHandler, SubstreamProtocol},
+    NetworkBehaviour, NetworkBehaviourAction, PollParameters,
 };
 use libp2p::{Multiaddr, PeerId};
 pub use methods::{ErrorMessage, RPCErrorResponse, RPCResponse, RequestId};
 pub use protocol::{RPCError, RPCProtocol, RPCRequest};
-use slog::o;
+use slog::{debug, o};
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
 
 pub(crate) mod codec;
 mod handler;
 pub mod methods;
 mod protocol;
 
+/// Identical requests to a peer made within this time of each other are sent once.
+const DEDUP_WINDOW: Duration = Duration::from_secs(1);
+/// How long to track a request awaiting its response, as a multiple of its response timeout.
+/// Handlers time out waiting well before.
+const PENDING_REQUEST_TTL_FACTOR: u32 = 2;
+/// The most requests held back from a single peer out of serving budget. Further requests are
+/// answered with an error.
+const MAX_THROTTLED_REQUESTS_PER_PEER: usize = 16;
+/// A held request is answered with an error if it cannot be served within this fraction of its
+/// response timeout, leaving time for the response to reach the peer before its handler gives
+/// up.
+const THROTTLE_DEADLINE_DIVISOR: u32 = 2;
 
 /// The return type used in the behaviour and the resultant event from the protocols handler.
 #[derive(Debug)]
@@ -52,6 +72,25 @@ impl RPCEvent {
 pub struct RPC<TSubstream> {
     /// Queue of events to processed.
     events: Vec<NetworkBehaviourAction<RPCEvent, RPCMessage>>,
+    /// Priority requests for the application, handed out in order ahead of `events`.
+    priority_events: VecDeque<RPCMessage>,
+    /// Requests awaiting a response, by peer and request id.
+    pending_requests: HashMap<(PeerId, RequestId), PendingRequest>,
+    /// Number of substreams open across all handlers, shared with each of them.
+    open_substreams: Arc<AtomicUsize>,
+    /// The limit on open substreams, if any.
+    max_open_substreams: Option<usize>,
+    /// Response timeouts for protocols that do not use the default, by message name.
+    response_timeouts: Arc<HashMap<String, Duration>>,
+    /// The work each peer may have served to it, if limited.
+    serving_budget: Option<ServingBudget>,
+    /// The remaining work budget of each peer that has been served.
+    budgets: HashMap<PeerId, WorkBudget>,
+    /// Requests from peers out of budget, held back until their budget replenishes, with the
+    /// time by which each must be released.
+    throttled: VecDeque<(PeerId, RPCEvent, Instant)>,
+    /// Wakes the behaviour when the next throttled peer's budget replenishes.
+    throttle_delay: Option<Delay>,
     /// Pins the generic substream.
     marker: PhantomData<(TSubstream)>,
     /// Slog logger for RPC behaviour.
@@ -59,24 +98,282 @@ pub struct RPC<TSubstream> {
 }
 
 impl<TSubstream> RPC<TSubstream> {
-    pub fn new(log: &slog::Logger) -> Self {
+    pub fn new(
+        max_open_substreams: Option<usize>,
+        serving_budget: Option<ServingBudget>,
+        response_timeouts: HashMap<String, Duration>,
+        log: &slog::Logger,
+    ) -> Self {
         let log = log.new(o!("Service" => "Libp2p-RPC"));
         RPC {
             events: Vec::new(),
+            priority_events: VecDeque::new(),
+            pending_requests: HashMap::new(),
+            open_substreams: Arc::new(AtomicUsize::new(0)),
+            max_open_substreams,
+            response_timeouts: Arc::new(response_timeouts),
+            serving_budget,
+            budgets: HashMap::new(),
+            throttled: VecDeque::new(),
+            throttle_delay: None,
             marker: PhantomData,
             _log: log,
         }
     }
 
-    /// Submits an RPC request.
+    /// The number of substreams open, or waiting to be opened, across all connections.
+    pub fn open_substreams(&self) -> usize {
+        self.open_substreams.load(Ordering::SeqCst)
+    }
+
+    /// Submits an RPC request. Sending an `Error` cancels the outbound request with that id, as
+    /// `cancel_rpc` does, without reporting the cancellation.
     ///
-    /// The peer must be connected for this to succeed.
+    /// The peer must be connected for this to succeed. A request identical to one sent to the
+    /// same peer within the dedup window is not sent again. Instead it receives a copy of the
+    /// earlier request's response.
     pub fn send_rpc(&mut self, peer_id: PeerId, rpc_event: RPCEvent) {
+        if let RPCEvent::Response(_, RPCErrorResponse::Success(RPCResponse::Message(data))) =
+            &rpc_event
+        {
+            self.charge(&peer_id, data.len() as u64);
+        }
+        if let RPCEvent::Error(id, _) = &rpc_event {
+            self.pending_requests.remove(&(peer_id.clone(), *id));
+        }
+        if let RPCEvent::Request(id, request) = &rpc_event {
+            if request.expect_response() {
+                let now = clock::now();
+                self.pending_requests
+                    .retain(|_, pending| now.duration_since(pending.sent) < pending.ttl);
+                let original = self.pending_requests.iter_mut().find(|((peer, _), pending)| {
+                    *peer == peer_id
+                        && pending.request == *request
+                        && now.duration_since(pending.sent) < DEDUP_WINDOW
+                });
+                if let Some(((_, original_id), pending)) = original {
+                    debug!(self._log, "Coalescing duplicate RPC request"; "peer_id" => format!("{:?}", peer_id), "request_id" => id, "original_id" => *original_id);
+                    pending.duplicates.push(*id);
+                    return;
+                }
+                let ttl = self.response_timeout(request) * PENDING_REQUEST_TTL_FACTOR;
+                self.pending_requests.insert(
+                    (peer_id.clone(), *id),
+                    PendingRequest {
+                        request: request.clone(),
+                        sent: now,
+                        ttl,
+                        duplicates: Vec::new(),
+                    },
+                );
+            }
+        }
         self.events.push(NetworkBehaviourAction::SendEvent {
             peer_id,
             event: rpc_event,
         });
     }
+
+    /// How long a response to the request may take.
+    fn response_timeout(&self, request: &RPCRequest) -> Duration {
+        self.response_timeouts
+            .get(request.message_name())
+            .cloned()
+            .unwrap_or_else(|| Duration::from_secs(handler::RESPONSE_TIMEOUT))
+    }
+
+    /// Charges the work of a response against the peer's serving budget.
+    fn charge(&mut self, peer_id: &PeerId, cost: u64) {
+        let budget = match self.serving_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        self.budgets
+            .entry(peer_id.clone())
+            .or_insert_with(|| WorkBudget::new(budget))
+            .consume(cost, clock::now());
+    }
+
+    /// Whether requests from the peer must be held back until its budget replenishes.
+    fn is_throttled(&mut self, peer_id: &PeerId) -> bool {
+        // requests queued behind a throttled request are held too, so they stay in order
+        if self.throttled.iter().any(|(peer, _, _)| peer == peer_id) {
+            return true;
+        }
+        let now = clock::now();
+        self.budgets
+            .get_mut(peer_id)
+            .map_or(false, |budget| !budget.has_budget(now))
+    }
+
+    /// Holds back a request from a peer out of budget, or refuses it if the peer already has
+    /// too many requests held or its budget will not replenish before the request's deadline.
+    fn throttle(&mut self, peer_id: PeerId, id: RequestId, request: RPCRequest) {
+        let now = clock::now();
+        let deadline = now + self.response_timeout(&request) / THROTTLE_DEADLINE_DIVISOR;
+        let held = self
+            .throttled
+            .iter()
+            .filter(|(peer, _, _)| *peer == peer_id)
+            .count();
+        let available_at = self
+            .budgets
+            .get(&peer_id)
+            .map_or(Some(now), |budget| budget.available_at(now));
+        if held >= MAX_THROTTLED_REQUESTS_PER_PEER
+            || available_at.map_or(true, |at| at > deadline)
+        {
+            self.refuse(peer_id, id);
+            return;
+        }
+        debug!(self._log, "Peer out of serving budget, holding request"; "peer_id" => format!("{:?}", peer_id), "request_id" => id);
+        self.throttled
+            .push_back((peer_id, RPCEvent::Request(id, request), deadline));
+    }
+
+    /// Answers a request from a peer out of budget with an error.
+    fn refuse(&mut self, peer_id: PeerId, id: RequestId) {
+        debug!(self._log, "Peer out of serving budget, refusing request"; "peer_id" => format!("{:?}", peer_id), "request_id" => id);
+        self.events.push(NetworkBehaviourAction::SendEvent {
+            peer_id,
+            event: RPCEvent::Response(
+                id,
+                RPCErrorResponse::ServerError(ErrorMessage {
+                    error_message: b"Serving budget exhausted".to_vec(),
+                }),
+            ),
+        });
+    }
+
+    /// Releases held requests from peers whose budget has replenished, refuses those whose
+    /// deadline has passed, and arranges to be woken when the next of either is due.
+    fn release_throttled(&mut self) {
+        if self.throttled.is_empty() {
+            self.throttle_delay = None;
+            return;
+        }
+        let now = clock::now();
+        let mut still_throttled = VecDeque::new();
+        let throttled = std::mem::replace(&mut self.throttled, VecDeque::new());
+        for (peer_id, event, deadline) in throttled {
+            let has_budget = self
+                .budgets
+                .get_mut(&peer_id)
+                .map_or(true, |budget| budget.has_budget(now));
+            if has_budget && !still_throttled.iter().any(|(peer, _, _)| *peer == peer_id) {
+                let priority = match &event {
+                    RPCEvent::Request(_, request) => request.is_priority(),
+                    _ => false,
+                };
+                if priority {
+                    self.priority_events.push_back(RPCMessage::RPC(peer_id, event));
+                } else {
+                    self.events
+                        .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                            peer_id, event,
+                        )));
+                }
+            } else if now >= deadline {
+                self.refuse(peer_id, event.id());
+            } else {
+                still_throttled.push_back((peer_id, event, deadline));
+            }
+        }
+        self.throttled = still_throttled;
+
+        let budgets = &self.budgets;
+        let next_release = self
+            .throttled
+            .iter()
+            .flat_map(|(peer_id, _, deadline)| {
+                budgets
+                    .get(peer_id)
+                    .and_then(|budget| budget.replenished_at(now))
+                    .into_iter()
+                    .chain(std::iter::once(*deadline))
+            })
+            .min();
+        let next_release = match next_release {
+            Some(next_release) => next_release,
+            None => {
+                self.throttle_delay = None;
+                return;
+            }
+        };
+        match self.throttle_delay.as_mut() {
+            Some(delay) if delay.deadline() != next_release => delay.reset(next_release),
+            Some(_) => {}
+            None => self.throttle_delay = Some(Delay::new(next_release)),
+        }
+        if let Some(delay) = self.throttle_delay.as_mut() {
+            if let Ok(Async::Ready(())) = delay.poll() {
+                futures::task::current().notify();
+            }
+        }
+    }
+
+    /// The requests sent to peers that are still awaiting a response.
+    pub fn pending_rpc_requests(&self) -> Vec<(PeerId, RequestId)> {
+        self.pending_requests.keys().cloned().collect()
+    }
+
+    /// Abandons a request awaiting a response, closing its substream. A `Cancelled` error is
+    /// reported for the request, and for any duplicates coalesced into it. Returns whether the
+    /// request was pending.
+    pub fn cancel_rpc(&mut self, peer_id: &PeerId, id: RequestId) -> bool {
+        let pending = match self.pending_requests.remove(&(peer_id.clone(), id)) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        self.events.push(NetworkBehaviourAction::SendEvent {
+            peer_id: peer_id.clone(),
+            event: RPCEvent::Error(id, RPCError::Cancelled),
+        });
+        for cancelled_id in std::iter::once(id).chain(pending.duplicates) {
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    peer_id.clone(),
+                    RPCEvent::Error(cancelled_id, RPCError::Cancelled),
+                )));
+        }
+        true
+    }
+
+    /// Abandons every request awaiting a response, as `cancel_rpc` does.
+    pub fn cancel_all_rpc(&mut self) {
+        for (peer_id, id) in self.pending_rpc_requests() {
+            self.cancel_rpc(&peer_id, id);
+        }
+    }
+
+    /// Passes the outcome of a request on to the duplicates coalesced into it.
+    fn complete_duplicates(&mut self, peer_id: &PeerId, event: &RPCEvent) {
+        let id = match event {
+            RPCEvent::Response(id, _) | RPCEvent::Error(id, _) => *id,
+            RPCEvent::Request(..) => return,
+        };
+        let duplicates = match self.pending_requests.remove(&(peer_id.clone(), id)) {
+            Some(pending) => pending.duplicates,
+            None => return,
+        };
+        for duplicate_id in duplicates {
+            let duplicate_event = match event {
+                RPCEvent::Response(_, response) => {
+                    RPCEvent::Response(duplicate_id, response.clone())
+                }
+                RPCEvent::Error(_, error) => RPCEvent::Error(
+                    duplicate_id,
+                    RPCError::Custom(format!("Coalesced request failed: {:?}", error)),
+                ),
+                RPCEvent::Request(..) => return,
+            };
+            self.events
+                .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    peer_id.clone(),
+                    duplicate_event,
+                )));
+        }
+    }
 }
 
 impl<TSubstream> NetworkBehaviour for RPC<TSubstream>
@@ -87,7 +384,13 @@ where
     type OutEvent = RPCMessage;
 
     fn new_handler(&mut self) -> Self::ProtocolsHandler {
-        Default::default()
+        RPCHandler::new(
+            SubstreamProtocol::new(RPCProtocol),
+            Duration::from_secs(30),
+            self.open_substreams.clone(),
+            self.max_open_substreams,
+            self.response_timeouts.clone(),
+        )
     }
 
     // handled by discovery
@@ -105,6 +408,8 @@ where
     }
 
     fn inject_disconnected(&mut self, peer_id: &PeerId, _: ConnectedPoint) {
+        self.budgets.remove(peer_id);
+        self.throttled.retain(|(peer, _, _)| peer != peer_id);
         // inform the rpc handler that the peer has disconnected
         self.events.push(NetworkBehaviourAction::GenerateEvent(
             RPCMessage::PeerDisconnected(peer_id.clone()),
@@ -116,11 +421,36 @@ where
         source: PeerId,
         event: <Self::ProtocolsHandler as ProtocolsHandler>::OutEvent,
     ) {
-        // send the event to the user
-        self.events
-            .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
-                source, event,
-            )));
+        let event = match event {
+            HandlerEvent::RPC(event) => event,
+            HandlerEvent::InboundRefused(request) => {
+                self.events
+                    .push(NetworkBehaviourAction::GenerateEvent(RPCMessage::InboundRefused {
+                        peer_id: source,
+                        request,
+                    }));
+                return;
+            }
+        };
+        if let RPCEvent::Request(id, request) = &event {
+            if self.is_throttled(&source) {
+                let (id, request) = (*id, request.clone());
+                self.throttle(source, id, request);
+                return;
+            }
+            if request.is_priority() {
+                // served ahead of any bulk requests already waiting to be handled
+                self.priority_events.push_back(RPCMessage::RPC(source, event));
+                return;
+            }
+        }
+        // send the event to the user, followed by copies for any duplicate requests
+        let index = self.events.len();
+        self.complete_duplicates(&source, &event);
+        self.events.insert(
+            index,
+            NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(source, event)),
+        );
     }
 
     fn poll(
@@ -132,6 +462,10 @@ where
             Self::OutEvent,
         >,
     > {
+        self.release_throttled();
+        if let Some(message) = self.priority_events.pop_front() {
+            return Async::Ready(NetworkBehaviourAction::GenerateEvent(message));
+        }
         if !self.events.is_empty() {
             return Async::Ready(self.events.remove(0));
         }
@@ -139,9 +473,261 @@ where
     }
 }
 
+/// A request sent to a peer, awaiting its response.
+struct PendingRequest {
+    /// The request sent.
+    request: RPCRequest,
+    /// When the request was sent.
+    sent: Instant,
+    /// How long after sending to stop tracking the request.
+    ttl: Duration,
+    /// The ids of identical requests coalesced into this one.
+    duplicates: Vec<RequestId>,
+}
+
+/// The work a peer may still have served to it, replenishing over time.
+struct WorkBudget {
+    /// The budget's limits.
+    budget: ServingBudget,
+    /// The work available, which goes negative when a response costs more than remained.
+    available: f64,
+    /// When `available` was last brought up to date.
+    updated: Instant,
+}
+
+impl WorkBudget {
+    fn new(budget: ServingBudget) -> Self {
+        WorkBudget {
+            budget,
+            available: budget.capacity as f64,
+            updated: clock::now(),
+        }
+    }
+
+    /// Adds the work regained since the last update.
+    fn replenish(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated);
+        let regained = elapsed.as_secs_f64() * self.budget.refill_per_sec as f64;
+        self.available = (self.available + regained).min(self.budget.capacity as f64);
+        self.updated = now;
+    }
+
+    fn consume(&mut self, cost: u64, now: Instant) {
+        self.replenish(now);
+        self.available -= cost as f64;
+    }
+
+    fn has_budget(&mut self, now: Instant) -> bool {
+        self.replenish(now);
+        self.available > 0.0
+    }
+
+    /// When the budget will next have work available, if it is used up and replenishes.
+    fn replenished_at(&self, now: Instant) -> Option<Instant> {
+        if self.available > 0.0 || self.budget.refill_per_sec == 0 {
+            return None;
+        }
+        let wait = (1.0 - self.available) / self.budget.refill_per_sec as f64;
+        Some(now + Duration::from_secs_f64(wait))
+    }
+
+    /// When the budget will have work available, which may be now, or `None` if it is used up
+    /// and never replenishes.
+    fn available_at(&self, now: Instant) -> Option<Instant> {
+        if self.available > 0.0 {
+            Some(now)
+        } else {
+            self.replenished_at(now)
+        }
+    }
+}
+
 /// Messages sent to the user from the RPC protocol.
 pub enum RPCMessage {
     RPC(PeerId, RPCEvent),
     PeerDialed(PeerId),
     PeerDisconnected(PeerId),
+    /// A peer's request was refused, without a response, because the open substream limit was
+    /// reached. It is not keyed by a request id, as it never had one.
+    InboundRefused { peer_id: PeerId, request: RPCRequest },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::test_utils::*;
+
+    fn budgeted_rpc() -> RPC<TestSubstream> {
+        let budget = ServingBudget {
+            capacity: 100,
+            refill_per_sec: 10,
+        };
+        RPC::new(None, Some(budget), HashMap::new(), &null_logger())
+    }
+
+    fn receive(rpc: &mut RPC<TestSubstream>, peer_id: &PeerId, id: RequestId) {
+        receive_request(rpc, peer_id, id, RPCRequest::Message(vec![1]));
+    }
+
+    fn receive_request(
+        rpc: &mut RPC<TestSubstream>,
+        peer_id: &PeerId,
+        id: RequestId,
+        request: RPCRequest,
+    ) {
+        rpc.inject_node_event(
+            peer_id.clone(),
+            HandlerEvent::RPC(RPCEvent::Request(id, request)),
+        );
+    }
+
+    fn respond(rpc: &mut RPC<TestSubstream>, peer_id: &PeerId, id: RequestId, size: usize) {
+        rpc.send_rpc(
+            peer_id.clone(),
+            RPCEvent::Response(id, RPCErrorResponse::Success(RPCResponse::Message(vec![0; size]))),
+        );
+    }
+
+    /// Takes the queued events in the order they are handed out, returning the ids of the
+    /// requests handed to the application and of those refused.
+    fn outcomes(rpc: &mut RPC<TestSubstream>) -> (Vec<RequestId>, Vec<RequestId>) {
+        let mut served = Vec::new();
+        let mut refused = Vec::new();
+        let priority_events: Vec<NetworkBehaviourAction<RPCEvent, RPCMessage>> = rpc
+            .priority_events
+            .drain(..)
+            .map(NetworkBehaviourAction::GenerateEvent)
+            .collect();
+        for event in priority_events.into_iter().chain(rpc.events.drain(..)) {
+            match event {
+                NetworkBehaviourAction::GenerateEvent(RPCMessage::RPC(
+                    _,
+                    RPCEvent::Request(id, _),
+                )) => served.push(id),
+                NetworkBehaviourAction::SendEvent {
+                    event: RPCEvent::Response(id, RPCErrorResponse::ServerError(_)),
+                    ..
+                } => refused.push(id),
+                _ => {}
+            }
+        }
+        (served, refused)
+    }
+
+    #[test]
+    fn requests_are_held_until_the_budget_replenishes() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut rpc = budgeted_rpc();
+            let peer_id = PeerId::random();
+            receive(&mut rpc, &peer_id, 1);
+            respond(&mut rpc, &peer_id, 1, 120);
+            assert_eq!(outcomes(&mut rpc), (vec![1], vec![]));
+
+            // 20 bytes over budget takes a little over two seconds to regain
+            receive(&mut rpc, &peer_id, 2);
+            rpc.release_throttled();
+            assert_eq!(outcomes(&mut rpc), (vec![], vec![]));
+            clock.advance(Duration::from_secs(3));
+            rpc.release_throttled();
+            assert_eq!(outcomes(&mut rpc), (vec![2], vec![]));
+        });
+    }
+
+    #[test]
+    fn requests_that_cannot_be_served_in_time_are_refused() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut rpc = budgeted_rpc();
+            let peer_id = PeerId::random();
+            receive(&mut rpc, &peer_id, 1);
+            // the budget would take 90 seconds to recover, past the response timeout
+            respond(&mut rpc, &peer_id, 1, 1000);
+            receive(&mut rpc, &peer_id, 2);
+            assert_eq!(outcomes(&mut rpc), (vec![1], vec![2]));
+        });
+    }
+
+    #[test]
+    fn held_requests_are_refused_at_their_deadline() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut rpc = budgeted_rpc();
+            let peer_id = PeerId::random();
+            receive(&mut rpc, &peer_id, 1);
+            respond(&mut rpc, &peer_id, 1, 120);
+            receive(&mut rpc, &peer_id, 2);
+            // a late response to an earlier request uses up the budget the held request waits on
+            respond(&mut rpc, &peer_id, 0, 1000);
+            assert_eq!(outcomes(&mut rpc), (vec![1], vec![]));
+
+            let deadline =
+                Duration::from_secs(handler::RESPONSE_TIMEOUT) / THROTTLE_DEADLINE_DIVISOR;
+            clock.advance(deadline);
+            rpc.release_throttled();
+            assert_eq!(outcomes(&mut rpc), (vec![], vec![2]));
+            assert!(rpc.throttled.is_empty());
+        });
+    }
+
+    #[test]
+    fn held_requests_are_capped_per_peer() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut rpc = budgeted_rpc();
+            let peer_id = PeerId::random();
+            receive(&mut rpc, &peer_id, 1);
+            respond(&mut rpc, &peer_id, 1, 120);
+            // two more requests than may be held
+            let last = MAX_THROTTLED_REQUESTS_PER_PEER + 3;
+            for id in 2..=last {
+                receive(&mut rpc, &peer_id, id);
+            }
+            let (served, refused) = outcomes(&mut rpc);
+            assert_eq!(served, vec![1]);
+            assert_eq!(refused, vec![last - 1, last]);
+
+            // other peers are unaffected
+            receive(&mut rpc, &PeerId::random(), 100);
+            assert_eq!(outcomes(&mut rpc), (vec![100], vec![]));
+        });
+    }
+
+    #[test]
+    fn priority_requests_are_handed_out_ahead_of_bulk_requests() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut rpc = RPC::new(None, None, HashMap::new(), &null_logger());
+            let peer_id = PeerId::random();
+            receive(&mut rpc, &peer_id, 1);
+            receive(&mut rpc, &peer_id, 2);
+            receive_request(&mut rpc, &peer_id, 3, RPCRequest::SampleRecent);
+            assert_eq!(outcomes(&mut rpc), (vec![3, 1, 2], vec![]));
+
+            receive(&mut rpc, &peer_id, 4);
+            receive_request(&mut rpc, &peer_id, 5, RPCRequest::SampleRecent);
+            receive_request(&mut rpc, &peer_id, 6, RPCRequest::SampleRecent);
+            assert_eq!(outcomes(&mut rpc), (vec![5, 6, 4], vec![]));
+        });
+    }
+
+    #[test]
+    fn priority_requests_are_held_while_out_of_budget() {
+        let clock = MockClock::new();
+        run_with_clock(&clock, || {
+            let mut rpc = budgeted_rpc();
+            let peer_id = PeerId::random();
+            receive(&mut rpc, &peer_id, 1);
+            respond(&mut rpc, &peer_id, 1, 120);
+            assert_eq!(outcomes(&mut rpc), (vec![1], vec![]));
+
+            receive_request(&mut rpc, &peer_id, 2, RPCRequest::SampleRecent);
+            rpc.release_throttled();
+            assert_eq!(outcomes(&mut rpc), (vec![], vec![]));
+            clock.advance(Duration::from_secs(3));
+            rpc.release_throttled();
+            assert_eq!(outcomes(&mut rpc), (vec![2], vec![]));
+        });
+    }
 }
@@ -37,6 +37,7 @@ impl UpgradeInfo for RPCProtocol {
     fn protocol_info(&self) -> Self::InfoIter {
         vec![
             ProtocolId::new("hello", "1", "ssz"),
+            ProtocolId::new("sample_recent", "1", "ssz"),
         ]
     }
 }
@@ -141,9 +142,12 @@ where
 // Combines all the RPC requests into a single enum to implement `UpgradeInfo` and
 // `OutboundUpgrade`
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RPCRequest {
     Message(Vec<u8>),
+    /// Asks for the peer's most recent item, answered with a single response. A cheap probe of
+    /// a peer's connectivity and freshness.
+    SampleRecent,
 }
 
 impl UpgradeInfo for RPCRequest {
@@ -161,16 +165,34 @@ impl RPCRequest {
     pub fn supported_protocols(&self) -> Vec<ProtocolId> {
         match self {
             // add more protocols when versions/encodings are supported
-            RPCRequest::Message(_) => vec![
-                ProtocolId::new("hello", "1", "ssz"),
+            RPCRequest::Message(_) | RPCRequest::SampleRecent => vec![
+                ProtocolId::new(self.message_name(), "1", "ssz"),
             ],
         }
     }
 
+    /// The message name of the protocol the request is sent over.
+    pub fn message_name(&self) -> &'static str {
+        match self {
+            RPCRequest::Message(_) => "hello",
+            RPCRequest::SampleRecent => "sample_recent",
+        }
+    }
+
+    /// Whether the request is cheap to serve and is handled ahead of bulk requests. It is still
+    /// held back while the peer is out of serving budget.
+    pub fn is_priority(&self) -> bool {
+        match self {
+            RPCRequest::Message(_) => false,
+            RPCRequest::SampleRecent => true,
+        }
+    }
+
      /// This specifies whether a stream should remain open and await a response, given a request.
     pub fn expect_response(&self) -> bool {
         match self {
             RPCRequest::Message(_) => true,
+            RPCRequest::SampleRecent => true,
         }
     }
 }
@@ -214,6 +236,10 @@ pub enum RPCError {
     IoError(io::Error),
     /// Waiting for a request/response timed out, or timer error'd.
     StreamTimeout,
+    /// The request was cancelled by the application.
+    Cancelled,
+    /// The stream closed part way through a response.
+    IncompleteResponse,
     /// Custom message.
     Custom(String),
 }
@@ -249,6 +275,8 @@ impl std::fmt::Display for RPCError {
             RPCError::InvalidProtocol(ref err) => write!(f, "Invalid Protocol: {}", err),
             RPCError::IoError(ref err) => write!(f, "IO Error: {}", err),
             RPCError::StreamTimeout => write!(f, "Stream Timeout"),
+            RPCError::Cancelled => write!(f, "Request Cancelled"),
+            RPCError::IncompleteResponse => write!(f, "Stream closed mid-response"),
             RPCError::Custom(ref err) => write!(f, "{}", err),
         }
     }
@@ -261,6 +289,8 @@ impl std::error::Error for RPCError {
             RPCError::InvalidProtocol(_) => None,
             RPCError::IoError(ref err) => Some(err),
             RPCError::StreamTimeout => None,
+            RPCError::Cancelled => None,
+            RPCError::IncompleteResponse => None,
             RPCError::Custom(_) => None,
         }
     }
@@ -1,15 +1,23 @@
+use crate::clock;
 use crate::config::*;
-use crate::behaviour::{Behaviour, BehaviourEvent, PubsubMessage};
+use crate::behaviour::{
+    Behaviour, BehaviourEvent, DropReason, EventFilter, MultiplexerKind, NegotiatedMultiplexers,
+    PubsubMessage,
+};
+use crate::discovery::{ConnectionLogEntry, DialExpectations};
 use crate::error;
 use crate::multiaddr::Protocol;
-use crate::rpc::{RPCEvent};
+use crate::rpc::{RPCEvent, RPCRequest};
+use crate::snapshot::{NetworkSnapshot, TopologyEdge, TopologyEdgeKind, TopologyGraph};
 use crate::NetworkConfig;
 use crate::{Topic, TopicHash};
 use crate::{BEACON_ATTESTATION_TOPIC, BEACON_BLOCK_TOPIC};
 use futures::prelude::*;
 use futures::Stream;
 use libp2p::core::{
+    either::EitherOutput,
     identity::Keypair,
+    ConnectedPoint,
     multiaddr::Multiaddr,
     muxing::StreamMuxerBox,
     nodes::Substream,
@@ -22,15 +30,26 @@ use slog::{debug, info, warn};
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio_timer::{Delay, Timeout};
 
 type Libp2pStream = Boxed<(PeerId, StreamMuxerBox), Error>;
 type Libp2pBehaviour = Behaviour<Substream<StreamMuxerBox>>;
 
 const NETWORK_KEY_FILENAME: &str = "key";
+/// How long to wait for a peer to report our address. Identify exchanges happen every five
+/// minutes.
+const EXTERNAL_ADDR_REFRESH_TIMEOUT: Duration = Duration::from_secs(6 * 60);
+/// How often messages the application's full channel could not take are offered again.
+const DELIVERY_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 pub const GOSSIP: &str = "GOSSIP";
 pub const RPC: &str = "RPC";
 pub const DISCOVERY: &str = "DISCOVERY";
+/// The category of messages from the application reporting that it has validated a gossip
+/// message.
+pub const VALIDATION: &str = "VALIDATION";
 
 pub struct Message {
     pub category: String,
@@ -38,6 +57,9 @@ pub struct Message {
     pub req_resp: u8,
     pub peer: String,
     pub value: Vec<u8>,
+    /// The id of a gossip message, passed back to `Service::validation_complete` once the
+    /// application has validated the message. Zero for other messages.
+    pub id: u64,
 }
 
 impl Message {
@@ -47,7 +69,8 @@ impl Message {
             command: command,
             req_resp: req_resp,
             peer: peer,
-            value: value
+            value: value,
+            id: 0,
         }
     }
 }
@@ -58,106 +81,471 @@ pub struct Service{
     //TODO: Make this private
     pub swarm: Swarm<Libp2pStream, Libp2pBehaviour>,
     /// This node's PeerId.
-    _local_peer_id: PeerId,
-    tx: std::sync::Mutex<sync::Sender<Message>>,
+    local_peer_id: PeerId,
+    /// The identity key, kept to restart discovery if an identity rotation fails.
+    local_key: Keypair,
+    /// The separate key gossip messages are authored with, if one is configured.
+    gossip_key: Option<Keypair>,
+    /// The configuration the swarm was built from, kept to rebuild it on an identity rotation.
+    config: NetworkConfig,
+    tx: std::sync::Mutex<sync::SyncSender<Message>>,
+    /// Messages waiting for room in `tx`, oldest first.
+    undelivered: VecDeque<Message>,
+    /// Wakes the service to offer undelivered messages again.
+    delivery_retry: Option<Delay>,
+    /// Whether the application has dropped the receiving end of `tx`.
+    consumer_gone: bool,
+    /// Whether we had any connected peers when last checked, to detect becoming isolated.
+    has_peers: bool,
+    /// The configured maintenance window, during which the node drains.
+    maintenance_window: Option<(Instant, Instant)>,
+    /// Wakes the service at the next edge of the maintenance window.
+    maintenance_delay: Option<Delay>,
+    /// Whether the service is currently draining for the maintenance window.
+    in_maintenance: bool,
+    /// Wakes the service when the next peer would become application idle.
+    app_idle_delay: Option<Delay>,
+    /// The most recent connection lifecycle events, oldest first.
+    connection_history: VecDeque<ConnectionLogEntry>,
+    /// The maximum number of connection events kept in the history.
+    event_history_len: usize,
     /// The libp2p logger handle.
     pub log: slog::Logger,
 }
 
 impl Service {
 
-    pub fn new(config: NetworkConfig, tx: std::sync::Mutex<sync::Sender<Message>>, log: slog::Logger) -> error::Result<Self> {
+    pub fn new(config: NetworkConfig, tx: std::sync::Mutex<sync::SyncSender<Message>>, log: slog::Logger) -> error::Result<Self> {
+        if config.discovery_resume_events >= config.discovery_pause_events {
+            return Err(format!(
+                "Discovery must resume below the backlog it pauses at, resume: {}, pause: {}",
+                config.discovery_resume_events, config.discovery_pause_events
+            )
+            .into());
+        }
+        if config.max_undelivered_events < config.discovery_pause_events {
+            return Err(format!(
+                "Undelivered messages must be allowed to reach the backlog discovery pauses at, limit: {}, pause: {}",
+                config.max_undelivered_events, config.discovery_pause_events
+            )
+            .into());
+        }
+
         // load the private key from CLI flag, disk or generate a new one
         let local_private_key = load_private_key(&config, &log);
 
         let local_peer_id = PeerId::from(local_private_key.public());
         info!(log, "Local peer id: {:?}", local_peer_id);
 
-        let mut swarm = {
-            // Set up the transport - tcp/ws with secio and mplex/yamux
-            let transport = build_transport(local_private_key.clone());
-            // network behaviour
-            let behaviour = Behaviour::new(&local_private_key, &config, &log)?;
-            Swarm::new(transport, behaviour, local_peer_id.clone())
+        // a separate gossip key stops our messages being linked to our network identity
+        let separate_gossip_key = config
+            .gossip_key_file
+            .as_ref()
+            .map(|key_file| load_key_file(key_file, &log));
+        if let Some(key) = separate_gossip_key.as_ref() {
+            info!(log, "Gossip author peer id: {:?}", key.public().into_peer_id());
+        }
+        let gossip_key = separate_gossip_key
+            .clone()
+            .unwrap_or_else(|| local_private_key.clone());
+
+        let mut swarm = build_swarm(&local_private_key, &gossip_key, &config, &log)?;
+        if config.dry_run {
+            info!(log, "Dry run: discovery disabled");
+            swarm.close_discovery();
+        }
+        listen(&mut swarm, &config, &log);
+        dial_libp2p_nodes(&mut swarm, &config, &log);
+
+        Ok(Service {
+            local_peer_id,
+            local_key: local_private_key,
+            swarm,
+            gossip_key: separate_gossip_key,
+            tx,
+            undelivered: VecDeque::new(),
+            delivery_retry: None,
+            consumer_gone: false,
+            has_peers: false,
+            maintenance_window: config.maintenance_window,
+            maintenance_delay: None,
+            in_maintenance: false,
+            app_idle_delay: None,
+            connection_history: VecDeque::with_capacity(config.event_history_len),
+            event_history_len: config.event_history_len,
+            config,
+            log,
+        })
+    }
+
+    /// This node's PeerId.
+    pub fn local_peer_id(&self) -> &PeerId {
+        &self.local_peer_id
+    }
+
+    /// Replaces the node's network identity with a new secp256k1 key.
+    ///
+    /// Peers know us by our peer id, so the whole swarm is rebuilt: every existing connection is
+    /// dropped, a new ENR is generated for the key and discovery bootstraps again from the
+    /// configured nodes. The new swarm is built before the old one is replaced, and if that
+    /// fails the old identity keeps running. State the application set on the old behaviour is
+    /// carried over as described by `Behaviour::inherit`; connection tags and messages out for
+    /// validation are dropped with their connections. The new key is saved to disk so it
+    /// survives a restart.
+    pub fn rotate_identity(&mut self, new_key: Keypair) -> error::Result<()> {
+        // discv5 requires a secp256k1 identity
+        match new_key {
+            Keypair::Secp256k1(_) => {}
+            _ => return Err("Only secp256k1 keys are supported for the network identity".into()),
+        }
+        let local_peer_id = PeerId::from(new_key.public());
+        info!(self.log, "Rotating network identity"; "old_peer_id" => format!("{:?}", self.local_peer_id), "new_peer_id" => format!("{:?}", local_peer_id));
+
+        // free the discovery port for the new discv5 instance
+        self.swarm.close_discovery();
+        let gossip_key = self.gossip_key.clone().unwrap_or_else(|| new_key.clone());
+        let mut swarm = match build_swarm(&new_key, &gossip_key, &self.config, &self.log) {
+            Ok(swarm) => swarm,
+            Err(e) => {
+                warn!(self.log, "Identity rotation failed, keeping the current identity"; "error" => format!("{}", e));
+                if !self.config.dry_run {
+                    self.swarm.reopen_discovery(&self.local_key, &self.config)?;
+                }
+                return Err(e);
+            }
         };
+        if self.config.dry_run {
+            swarm.close_discovery();
+        }
+        swarm.inherit(&mut self.swarm);
+        // keep the disconnects of the old identity's connections in the history
+        self.record_connection_events();
+        // replacing the swarm drops the old identity's connections and TCP listener, so the new
+        // swarm can only listen once it is in place
+        self.swarm = swarm;
+        listen(&mut self.swarm, &self.config, &self.log);
+        dial_libp2p_nodes(&mut self.swarm, &self.config, &self.log);
+
+        save_key_file(
+            &self.config.network_dir.join(NETWORK_KEY_FILENAME),
+            &new_key,
+            &self.log,
+        );
+        self.local_peer_id = local_peer_id;
+        self.local_key = new_key;
+        Ok(())
+    }
 
-        // listen on the specified address
-        let listen_multiaddr = {
-            let mut m = Multiaddr::from(config.listen_address);
-            m.push(Protocol::Tcp(config.libp2p_port));
-            m
+    /// Drains the node while inside the maintenance window, and resumes once it has passed.
+    fn poll_maintenance_window(&mut self) {
+        let (start, end) = match self.maintenance_window {
+            Some(window) => window,
+            None => return,
         };
+        loop {
+            let now = clock::now();
+            let in_window = now >= start && now < end;
+            if in_window != self.in_maintenance {
+                self.in_maintenance = in_window;
+                self.swarm.set_draining(in_window);
+                if in_window {
+                    info!(self.log, "Maintenance window started, draining connections");
+                } else {
+                    info!(self.log, "Maintenance window ended, accepting connections");
+                }
+            }
 
-        match Swarm::listen_on(&mut swarm, listen_multiaddr.clone()) {
-            Ok(_) => {
-                let mut log_address = listen_multiaddr;
-                log_address.push(Protocol::P2p(local_peer_id.clone().into()));
-                info!(log, "Listening established"; "address" => format!("{}", log_address));
+            // schedule a wake-up for the next edge of the window
+            let next_edge = if now < start {
+                start
+            } else if now < end {
+                end
+            } else {
+                self.maintenance_delay = None;
+                return;
+            };
+            let delay = self
+                .maintenance_delay
+                .get_or_insert_with(|| Delay::new(next_edge));
+            delay.reset(next_edge);
+            match delay.poll() {
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => return,
+                Err(e) => {
+                    warn!(self.log, "Maintenance window timer failed"; "error" => format!("{:?}", e));
+                    return;
+                }
             }
-            Err(err) => warn!(
-                log,
-                "Cannot listen on: {} because: {:?}", listen_multiaddr, err
-            ),
-        };
+        }
+    }
 
-        // attempt to connect to user-input libp2p nodes
-        for multiaddr in config.libp2p_nodes {
-            match Swarm::dial_addr(&mut swarm, multiaddr.clone()) {
-                Ok(()) => debug!(log, "Dialing libp2p peer"; "address" => format!("{}", multiaddr)),
-                Err(err) => debug!(
-                    log,
-                    "Could not connect to peer"; "address" => format!("{}", multiaddr), "Error" => format!("{:?}", err)
-                ),
+    /// Queues peers that have been application idle for `app_idle_timeout` for disconnection,
+    /// and schedules a wake-up for when the next peer would become idle.
+    fn poll_app_idle(&mut self) {
+        let timeout = match self.config.app_idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        loop {
+            let next_idle = match self.swarm.disconnect_app_idle_peers(timeout) {
+                Some(next_idle) => next_idle,
+                None => {
+                    self.app_idle_delay = None;
+                    return;
+                }
             };
+            let delay = self
+                .app_idle_delay
+                .get_or_insert_with(|| Delay::new(next_idle));
+            delay.reset(next_idle);
+            match delay.poll() {
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => return,
+                Err(e) => {
+                    warn!(self.log, "Application idle timer failed"; "error" => format!("{:?}", e));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The most recent connection lifecycle events, oldest first.
+    pub fn recent_connection_events(&self) -> Vec<ConnectionLogEntry> {
+        self.connection_history.iter().cloned().collect()
+    }
+
+    /// Moves new connection events from the behaviour into the bounded history.
+    fn record_connection_events(&mut self) {
+        for entry in self.swarm.take_connection_events() {
+            if self.event_history_len == 0 {
+                continue;
+            }
+            if self.connection_history.len() >= self.event_history_len {
+                self.connection_history.pop_front();
+            }
+            self.connection_history.push_back(entry);
         }
+    }
 
-        // subscribe to default gossipsub topics
-        let mut topics = vec![];
+    /// Closes the connections of any peers the behaviour has queued for disconnection.
+    fn disconnect_queued_peers(&mut self) {
+        for peer_id in self.swarm.take_peers_to_disconnect() {
+            debug!(self.log, "Disconnecting peer"; "peer_id" => format!("{:?}", peer_id));
+            // banning closes the connection, unbanning allows the peer to connect again later
+            Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+            Swarm::unban_peer_id(&mut self.swarm, peer_id.clone());
+            self.swarm.peer_disconnected(peer_id);
+        }
+    }
 
+    /// The number of peers known to discovery. Compared with the connected peer count this
+    /// indicates how healthy discovery is.
+    pub fn known_peer_count(&self) -> usize {
+        self.swarm.known_peer_count()
+    }
 
-       /* Here we subscribe to all the required gossipsub topics required for interop.
-         * The topic builder adds the required prefix and postfix to the hardcoded topics that we
-         * must subscribe to.
-         */
-        let topic_builder = |topic| {
-            Topic::new(format!(
-                "/{}/{}/{}",
-                TOPIC_PREFIX, topic, TOPIC_ENCODING_POSTFIX,
-            ))
-        };
-        topics.push(topic_builder(BEACON_BLOCK_TOPIC));
-        topics.push(topic_builder(BEACON_ATTESTATION_TOPIC));
-        topics.push(topic_builder(VOLUNTARY_EXIT_TOPIC));
-        topics.push(topic_builder(PROPOSER_SLASHING_TOPIC));
-        topics.push(topic_builder(ATTESTER_SLASHING_TOPIC));
-
-        // Add any topics specified by the user
-        topics.append(
-            &mut config
-                .topics
+    /// Captures the service's observable network state. Diffing snapshots taken either side of
+    /// an operation shows what it changed.
+    pub fn snapshot(&self) -> NetworkSnapshot {
+        NetworkSnapshot {
+            peers: self
+                .swarm
+                .connected_peers()
+                .iter()
+                .map(|peer_id| peer_id.to_base58())
+                .collect(),
+            subscriptions: self
+                .swarm
+                .subscriptions()
+                .iter()
+                .map(|topic| topic.as_str().to_string())
+                .collect(),
+            banned: self
+                .swarm
+                .banned_peers()
                 .iter()
-                .cloned()
-                .map(|s| Topic::new(s))
+                .map(|peer_id| peer_id.to_base58())
                 .collect(),
+        }
+    }
+
+    /// Exports our view of the network as a graph of peers and topics.
+    pub fn topology_graph(&self) -> TopologyGraph {
+        let local_peer = self.local_peer_id.to_base58();
+        let mut graph = TopologyGraph {
+            local_peer: local_peer.clone(),
+            ..Default::default()
+        };
+        graph.add_subscriptions(
+            &local_peer,
+            self.swarm.subscriptions().iter().map(TopicHash::as_str),
         );
+        for peer_id in self.swarm.connected_peers() {
+            let peer = peer_id.to_base58();
+            graph.peers.insert(peer.clone());
+            graph.edges.insert(TopologyEdge {
+                from: local_peer.clone(),
+                to: peer.clone(),
+                kind: TopologyEdgeKind::Connection,
+            });
+            if let Some(topics) = self.swarm.peer_topics(peer_id) {
+                graph.add_subscriptions(&peer, topics.iter().map(TopicHash::as_str));
+            }
+        }
+        graph
+    }
 
-        let mut subscribed_topics = vec![];
-        for topic in topics {
-            if swarm.subscribe(topic.clone()) {
-                debug!(log, "Subscribed to topic: {:?}", topic);
-                subscribed_topics.push(topic);
-            } else {
-                warn!(log, "Could not subscribe to topic: {:?}", topic)
+    /// Passes a message to the application. Messages the application's channel has no room for
+    /// wait, in order, and discovery queries pause while too many are waiting. Once
+    /// `max_undelivered_events` are waiting further messages are dropped. If the application has
+    /// gone the message is dropped, and the service responds according to its `on_consumer_gone`
+    /// policy on its next poll. Returns whether the message was kept.
+    pub fn deliver(&mut self, message: Message) -> bool {
+        if self.consumer_gone {
+            return false;
+        }
+        if self.undelivered.len() >= self.config.max_undelivered_events {
+            debug!(self.log, "Application message backlog full, dropping message"; "backlog" => self.undelivered.len());
+            self.swarm.record_drop(DropReason::DeliveryBacklogFull);
+            return false;
+        }
+        self.undelivered.push_back(message);
+        self.flush_undelivered();
+        true
+    }
+
+    /// Offers waiting messages to the application until its channel is full, pausing or
+    /// resuming discovery queries as the backlog crosses its thresholds.
+    fn flush_undelivered(&mut self) {
+        while let Some(message) = self.undelivered.pop_front() {
+            match self.tx.lock().unwrap().try_send(message) {
+                Ok(()) => {}
+                Err(sync::TrySendError::Full(message)) => {
+                    self.undelivered.push_front(message);
+                    break;
+                }
+                Err(sync::TrySendError::Disconnected(_)) => {
+                    warn!(self.log, "Application message receiver dropped"; "policy" => format!("{:?}", self.config.on_consumer_gone));
+                    self.consumer_gone = true;
+                    self.undelivered.clear();
+                }
             }
         }
-        info!(log, "Subscribed to topics"; "topics" => format!("{:?}", subscribed_topics.iter().map(|t| format!("{}", t)).collect::<Vec<String>>()));
-        Ok(Service {
-            _local_peer_id: local_peer_id,
-            swarm,
-            tx,
-            log,
+        let backlog = self.undelivered.len();
+        if backlog >= self.config.discovery_pause_events && !self.swarm.discovery_paused() {
+            warn!(self.log, "Application is behind on messages, pausing discovery"; "backlog" => backlog);
+            self.swarm.set_discovery_paused(true);
+        } else if backlog <= self.config.discovery_resume_events && self.swarm.discovery_paused() {
+            info!(self.log, "Application caught up on messages, resuming discovery"; "backlog" => backlog);
+            self.swarm.set_discovery_paused(false);
+        }
+    }
+
+    /// Offers waiting messages again, and arranges to be woken to retry while any remain.
+    fn poll_undelivered(&mut self) {
+        loop {
+            if self.undelivered.is_empty() {
+                self.delivery_retry = None;
+                return;
+            }
+            self.flush_undelivered();
+            if self.undelivered.is_empty() {
+                continue;
+            }
+            let delay = self
+                .delivery_retry
+                .get_or_insert_with(|| Delay::new(clock::now() + DELIVERY_RETRY_INTERVAL));
+            match delay.poll() {
+                Ok(Async::Ready(())) => {
+                    delay.reset(clock::now() + DELIVERY_RETRY_INTERVAL);
+                }
+                Ok(Async::NotReady) => return,
+                Err(e) => {
+                    warn!(self.log, "Delivery retry timer failed"; "error" => format!("{:?}", e));
+                    self.delivery_retry = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Passes beacon block and attestation gossip to the application. Returns whether the
+    /// message was passed on.
+    fn forward_gossip(&mut self, id: u64, topics: &[TopicHash], message: &PubsubMessage) -> bool {
+        match message {
+            PubsubMessage::Block(data) | PubsubMessage::Attestation(data) => self.deliver(Message {
+                category: GOSSIP.to_string(),
+                command: topics[0].to_string(),
+                req_resp: Default::default(),
+                peer: Default::default(),
+                value: data.clone(),
+                id,
+            }),
+            PubsubMessage::Unknown(_) => false,
+        }
+    }
+
+    /// Hands a received gossip message to the application. Messages the application is not
+    /// given release their validation slots at once, as nothing will validate them.
+    fn receive_gossip(&mut self, id: u64, topics: &[TopicHash], message: &PubsubMessage) {
+        if !self.forward_gossip(id, topics, message) {
+            self.swarm.validation_complete(id);
+        }
+    }
+
+    /// Informs the service that the application has finished validating the gossip message with
+    /// the given id, freeing its validation slot. Returns false if the message was not out for
+    /// validation, such as when its id has already been completed.
+    pub fn validation_complete(&mut self, id: u64) -> bool {
+        self.swarm.validation_complete(id)
+    }
+
+    /// Reports a change between having no connected peers and having some.
+    fn poll_isolation(&mut self) -> Option<Libp2pEvent> {
+        let has_peers = self.swarm.num_connected_peers() > 0;
+        if has_peers == self.has_peers {
+            return None;
+        }
+        self.has_peers = has_peers;
+        if has_peers {
+            info!(self.log, "Connected to a peer, no longer isolated");
+            Some(Libp2pEvent::Deisolated)
+        } else {
+            warn!(self.log, "Lost the last connected peer, isolated");
+            Some(Libp2pEvent::Isolated)
+        }
+    }
+
+    /// Selects which events the service produces. Filtered events are dropped where they are
+    /// generated, so high-volume events an application does not need cost little.
+    pub fn set_event_filter(&mut self, filter: EventFilter) {
+        self.swarm.set_event_filter(filter);
+    }
+
+    /// Asks a trusted peer for the address it sees us at, updating the IP in our ENR to match.
+    /// Identify has no on-demand query, so the peer reports the address at its next periodic
+    /// identify exchange with us. Fails if the peer is not connected, disconnects first, or does
+    /// not report in time.
+    pub fn refresh_external_addr_via(
+        &mut self,
+        peer_id: PeerId,
+    ) -> impl Future<Item = Multiaddr, Error = error::Error> {
+        let observed_addr = self.swarm.request_observed_addr(peer_id);
+        Timeout::new(observed_addr, EXTERNAL_ADDR_REFRESH_TIMEOUT).map_err(|e| -> error::Error {
+            if e.is_elapsed() {
+                "Timed out waiting for the peer to report our address".into()
+            } else {
+                "Peer not connected, or disconnected before reporting our address".into()
+            }
         })
     }
+
+    /// Shuts down the service's network resources. The discovery socket is closed explicitly so
+    /// its port can be rebound straight away on a quick restart.
+    pub fn shutdown(&mut self) {
+        info!(self.log, "Shutting down libp2p service");
+        self.swarm.close_discovery();
+    }
 }
 
 impl Stream for Service {
@@ -165,63 +553,271 @@ impl Stream for Service {
     type Error = crate::error::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.consumer_gone {
+            return match self.config.on_consumer_gone {
+                ConsumerGonePolicy::Shutdown => Ok(Async::Ready(None)),
+                ConsumerGonePolicy::Pause => Ok(Async::NotReady),
+            };
+        }
+        self.poll_maintenance_window();
+        self.poll_undelivered();
         loop {
-            match self.swarm.poll() {
+            self.poll_app_idle();
+            self.disconnect_queued_peers();
+            self.record_connection_events();
+            if let Some(event) = self.poll_isolation() {
+                return Ok(Async::Ready(Some(event)));
+            }
+            let event = if self.swarm.validation_backlogged() {
+                // stop reading from the network until the application frees validation slots,
+                // still handing out the messages already let through
+                match self.swarm.take_event() {
+                    Some(event) => event,
+                    None => break,
+                }
+            } else {
+                match self.swarm.poll() {
+                    Ok(Async::Ready(Some(event))) => event,
+                    Ok(Async::Ready(None)) => unreachable!("Swarm stream shouldn't end"),
+                    Ok(Async::NotReady) => {
+                        // disconnections queued by the last poll produce events of their own
+                        if !self.swarm.has_peers_to_disconnect() {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ => break,
+                }
+            };
+            match event {
                 //Behaviour events
-                Ok(Async::Ready(Some(event))) => match event {
-                    BehaviourEvent::PubsubMessage {
+                BehaviourEvent::PubsubMessage {
+                    id,
+                    source,
+                    topics,
+                    message,
+                } => {
+                    //debug!(self.log, "Gossipsub message received"; "Message" => format!("{:?}", topics[0]));
+                    self.receive_gossip(id, &topics, &message);
+                    return Ok(Async::Ready(Some(Libp2pEvent::PubsubMessage {
+                        id,
                         source,
                         topics,
                         message,
-                    } => {
-                        //debug!(self.log, "Gossipsub message received"; "Message" => format!("{:?}", topics[0]));
-                        if topics[0].to_string() == format!("/{}/{}/{}",TOPIC_PREFIX, BEACON_BLOCK_TOPIC, TOPIC_ENCODING_POSTFIX) {
-                            self.tx.lock().unwrap().send(Message {
-                                category: GOSSIP.to_string(),
-                                command: topics[0].to_string(),
-                                req_resp: Default::default(),
-                                peer: Default::default(),
-                                value: message.clone()
-                            }).unwrap();
-                        } else if topics[0].to_string() == format!("/{}/{}/{}",TOPIC_PREFIX, BEACON_ATTESTATION_TOPIC, TOPIC_ENCODING_POSTFIX) {
-                            self.tx.lock().unwrap().send(Message {
-                                category: GOSSIP.to_string(),
-                                command: topics[0].to_string(),
-                                req_resp: Default::default(),
-                                peer: Default::default(),
-                                value: message.clone()
-                            }).unwrap();
-                        } 
-                        return Ok(Async::Ready(Some(Libp2pEvent::PubsubMessage {
-                            source,
-                            topics,
-                            message,
-                        })));
-                    }
-                    BehaviourEvent::RPC(peer_id, event) => {
-                        //debug!(self.log,"Received RPC message from: {:?}", peer_id);
-                        return Ok(Async::Ready(Some(Libp2pEvent::RPC(peer_id, event))));
-                    }
-                    BehaviourEvent::PeerDialed(peer_id) => {
-                         return Ok(Async::Ready(Some(Libp2pEvent::PeerDialed(peer_id))));
-                    }
-                    BehaviourEvent::PeerDisconnected(peer_id) => {
-                        return Ok(Async::Ready(Some(Libp2pEvent::PeerDisconnected(peer_id))));
-                    }
-                },
-                Ok(Async::Ready(None)) => unreachable!("Swarm stream shouldn't end"),
-                Ok(Async::NotReady) => break,
-                _ => break,
+                    })));
+                }
+                BehaviourEvent::ChunkedMessageComplete {
+                    id,
+                    source,
+                    topic,
+                    data,
+                } => {
+                    let topics = vec![topic];
+                    let message = PubsubMessage::from_topics(&topics, data);
+                    self.receive_gossip(id, &topics, &message);
+                    return Ok(Async::Ready(Some(Libp2pEvent::PubsubMessage {
+                        id,
+                        source,
+                        topics,
+                        message,
+                    })));
+                }
+                BehaviourEvent::RPC(peer_id, event) => {
+                    //debug!(self.log,"Received RPC message from: {:?}", peer_id);
+                    return Ok(Async::Ready(Some(Libp2pEvent::RPC(peer_id, event))));
+                }
+                BehaviourEvent::InboundRefused { peer_id, request } => {
+                    return Ok(Async::Ready(Some(Libp2pEvent::InboundRefused {
+                        peer_id,
+                        request,
+                    })));
+                }
+                BehaviourEvent::PeerDialed(peer_id) => {
+                     return Ok(Async::Ready(Some(Libp2pEvent::PeerDialed(peer_id))));
+                }
+                BehaviourEvent::PeerDisconnected(peer_id) => {
+                    return Ok(Async::Ready(Some(Libp2pEvent::PeerDisconnected(peer_id))));
+                }
+                BehaviourEvent::ProbeReceived { origin, latency } => {
+                    return Ok(Async::Ready(Some(Libp2pEvent::ProbeReceived {
+                        origin,
+                        latency,
+                    })));
+                }
+                BehaviourEvent::PeerIdMismatch { claimed, derived } => {
+                    return Ok(Async::Ready(Some(Libp2pEvent::PeerIdMismatch {
+                        claimed,
+                        derived,
+                    })));
+                }
             }
         }
+        self.record_connection_events();
+        if let Some(event) = self.poll_isolation() {
+            return Ok(Async::Ready(Some(event)));
+        }
         Ok(Async::NotReady)
     }
 }
 
+/// Builds the swarm for a network identity and subscribes it to the configured topics.
+fn build_swarm(
+    local_private_key: &Keypair,
+    gossip_key: &Keypair,
+    config: &NetworkConfig,
+    log: &slog::Logger,
+) -> error::Result<Swarm<Libp2pStream, Libp2pBehaviour>> {
+    let local_peer_id = PeerId::from(local_private_key.public());
+    let mut swarm = {
+        // Set up the transport - tcp/ws with secio and mplex/yamux
+        let dial_expectations = DialExpectations::default();
+        let multiplexers = NegotiatedMultiplexers::default();
+        check_transport_buffers(&config.transport_buffers, log)?;
+        let transport = build_transport(
+            local_private_key.clone(),
+            config.peer_authorizer.clone(),
+            dial_expectations.clone(),
+            multiplexers.clone(),
+            config.transport_buffers,
+        );
+        // network behaviour
+        let behaviour = Behaviour::new(
+            local_private_key,
+            gossip_key,
+            config,
+            dial_expectations,
+            multiplexers,
+            log,
+        )?;
+        Swarm::new(transport, behaviour, local_peer_id)
+    };
+
+    // subscribe to default gossipsub topics
+    let mut topics = vec![];
+
+
+    /* Here we subscribe to all the required gossipsub topics required for interop.
+     * The topic builder adds the required prefix and postfix to the hardcoded topics that we
+     * must subscribe to.
+     */
+    let topic_builder = |topic| {
+        Topic::new(format!(
+            "/{}/{}/{}",
+            TOPIC_PREFIX, topic, TOPIC_ENCODING_POSTFIX,
+        ))
+    };
+    topics.push(topic_builder(BEACON_BLOCK_TOPIC));
+    topics.push(topic_builder(BEACON_ATTESTATION_TOPIC));
+    topics.push(topic_builder(VOLUNTARY_EXIT_TOPIC));
+    topics.push(topic_builder(PROPOSER_SLASHING_TOPIC));
+    topics.push(topic_builder(ATTESTER_SLASHING_TOPIC));
+    if config.enable_probes {
+        topics.push(topic_builder(PROBE_TOPIC));
+    }
+
+    // Add any topics specified by the user
+    topics.append(
+        &mut config
+            .topics
+            .iter()
+            .cloned()
+            .map(|s| Topic::new(s))
+            .collect(),
+    );
+
+    let mut subscribed_topics = vec![];
+    for topic in topics {
+        if swarm.subscribe(topic.clone()) {
+            debug!(log, "Subscribed to topic: {:?}", topic);
+            subscribed_topics.push(topic);
+        } else {
+            warn!(log, "Could not subscribe to topic: {:?}", topic)
+        }
+    }
+    info!(log, "Subscribed to topics"; "topics" => format!("{:?}", subscribed_topics.iter().map(|t| format!("{}", t)).collect::<Vec<String>>()));
+    Ok(swarm)
+}
+
+/// Starts listening on the configured address.
+fn listen(
+    swarm: &mut Swarm<Libp2pStream, Libp2pBehaviour>,
+    config: &NetworkConfig,
+    log: &slog::Logger,
+) {
+    // listen on the specified address
+    let listen_multiaddr = {
+        let mut m = Multiaddr::from(config.listen_address);
+        m.push(Protocol::Tcp(config.libp2p_port));
+        m
+    };
+    if config.dry_run {
+        info!(log, "Dry run: would listen"; "address" => format!("{}", listen_multiaddr));
+        return;
+    }
+
+    match Swarm::listen_on(swarm, listen_multiaddr.clone()) {
+        Ok(_) => {
+            let mut log_address = listen_multiaddr;
+            log_address.push(Protocol::P2p(Swarm::local_peer_id(swarm).clone().into()));
+            info!(log, "Listening established"; "address" => format!("{}", log_address));
+        }
+        Err(err) => warn!(
+            log,
+            "Cannot listen on: {} because: {:?}", listen_multiaddr, err
+        ),
+    };
+}
+
+/// Attempts to connect to the libp2p nodes given in the configuration.
+fn dial_libp2p_nodes(
+    swarm: &mut Swarm<Libp2pStream, Libp2pBehaviour>,
+    config: &NetworkConfig,
+    log: &slog::Logger,
+) {
+    // attempt to connect to user-input libp2p nodes
+    for multiaddr in config.libp2p_nodes.iter() {
+        if config.dry_run {
+            info!(log, "Dry run: would dial libp2p peer"; "address" => format!("{}", multiaddr));
+            continue;
+        }
+        match Swarm::dial_addr(swarm, multiaddr.clone()) {
+            Ok(()) => debug!(log, "Dialing libp2p peer"; "address" => format!("{}", multiaddr)),
+            Err(err) => debug!(
+                log,
+                "Could not connect to peer"; "address" => format!("{}", multiaddr), "Error" => format!("{:?}", err)
+            ),
+        };
+    }
+}
+
 /// The implementation supports TCP/IP, WebSockets over TCP/IP, secio as the encryption layer, and
 /// mplex or yamux as the multiplexing layer.
-fn build_transport(local_private_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox), Error> {
-    let transport = libp2p::tcp::TcpConfig::new();
+///
+/// If a peer authorizer is given, connections it refuses are closed once the multiplexer is
+/// negotiated, before any protocol can run. Dialed connections answered by a peer other than the
+/// one expected at the address are closed in the same way.
+fn build_transport(
+    local_private_key: Keypair,
+    peer_authorizer: Option<PeerAuthorizer>,
+    dial_expectations: DialExpectations,
+    multiplexers: NegotiatedMultiplexers,
+    buffers: TransportBuffers,
+) -> Boxed<(PeerId, StreamMuxerBox), Error> {
+    let mut transport = libp2p::tcp::TcpConfig::new();
+    if let Some(size) = buffers.tcp_send_buffer_size {
+        transport = transport.send_buffer_size(size);
+    }
+    if let Some(size) = buffers.tcp_recv_buffer_size {
+        transport = transport.recv_buffer_size(size);
+    }
+    let mut yamux_config = libp2p::yamux::Config::default();
+    if let Some(window) = buffers.yamux_receive_window {
+        yamux_config.set_receive_window(window);
+    }
+    let mut mplex_config = libp2p::mplex::MplexConfig::new();
+    if let Some(size) = buffers.mplex_max_buffer_size {
+        mplex_config.set_max_buffer_size(size);
+    }
     let transport = libp2p::dns::DnsConfig::new(transport);
     #[cfg(feature = "libp2p-websocket")]
     let transport = {
@@ -231,39 +827,114 @@ fn build_transport(local_private_key: Keypair) -> Boxed<(PeerId, StreamMuxerBox)
     transport
         .with_upgrade(secio::SecioConfig::new(local_private_key))
         .and_then(move |out, endpoint| {
+            let remote_key = out.remote_key.clone().into_protobuf_encoding();
             let peer_id = out.remote_key.into_peer_id();
             let peer_id2 = peer_id.clone();
-            let upgrade = core::upgrade::SelectUpgrade::new(
-                libp2p::yamux::Config::default(),
-                libp2p::mplex::MplexConfig::new(),
-            )
+            let peer_authorizer = peer_authorizer.clone();
+            let multiplexers = multiplexers.clone();
+            let mismatch = match &endpoint {
+                ConnectedPoint::Dialer { address } => dial_expectations.check(address, &peer_id),
+                ConnectedPoint::Listener { .. } => None,
+            };
+            let upgrade = core::upgrade::SelectUpgrade::new(yamux_config.clone(), mplex_config.clone())
             // TODO: use a single `.map` instead of two maps
             .map_inbound(move |muxer| (peer_id, muxer))
             .map_outbound(move |muxer| (peer_id2, muxer));
 
             core::upgrade::apply(out.stream, upgrade, endpoint)
-                .map(|(id, muxer)| (id, core::muxing::StreamMuxerBox::new(muxer)))
+                .map_err(|err| Error::new(ErrorKind::Other, err))
+                .and_then(move |(id, muxer)| {
+                    if let Some(expected) = mismatch {
+                        return Err(Error::new(
+                            ErrorKind::PermissionDenied,
+                            format!("Expected peer {:?} but reached {:?}", expected, id),
+                        ));
+                    }
+                    if let Some(authorizer) = peer_authorizer {
+                        if !authorizer.authorize(&id, &remote_key) {
+                            return Err(Error::new(
+                                ErrorKind::PermissionDenied,
+                                format!("Peer not authorized: {:?}", id),
+                            ));
+                        }
+                    }
+                    let kind = match &muxer {
+                        EitherOutput::First(_) => MultiplexerKind::Yamux,
+                        EitherOutput::Second(_) => MultiplexerKind::Mplex,
+                    };
+                    multiplexers.record(id.clone(), kind);
+                    Ok((id, core::muxing::StreamMuxerBox::new(muxer)))
+                })
         })
         .with_timeout(Duration::from_secs(20))
         .map_err(|err| Error::new(ErrorKind::Other, err))
         .boxed()
 }
 
+/// The smallest receive window yamux allows.
+const YAMUX_MIN_RECEIVE_WINDOW: u32 = 256 * 1024;
+
+/// Warns of socket buffer sizes the OS will not honour, as the OS silently caps them at its
+/// configured maximums. Multiplexer settings yamux would reject are an error.
+fn check_transport_buffers(buffers: &TransportBuffers, log: &slog::Logger) -> error::Result<()> {
+    let os_limits = [
+        (buffers.tcp_send_buffer_size, "/proc/sys/net/core/wmem_max", "TCP send buffer"),
+        (buffers.tcp_recv_buffer_size, "/proc/sys/net/core/rmem_max", "TCP receive buffer"),
+    ];
+    for (size, limit_file, name) in os_limits.iter() {
+        let size = match size {
+            Some(size) => *size,
+            None => continue,
+        };
+        let limit = std::fs::read_to_string(limit_file)
+            .ok()
+            .and_then(|limit| limit.trim().parse::<usize>().ok());
+        if let Some(limit) = limit {
+            if size > limit {
+                warn!(log, "Buffer size exceeds the OS limit and will be capped"; "buffer" => *name, "size" => size, "limit" => limit);
+            }
+        }
+    }
+    if let Some(window) = buffers.yamux_receive_window {
+        if window < YAMUX_MIN_RECEIVE_WINDOW {
+            return Err(format!(
+                "Yamux receive window of {} bytes is below the minimum of {} bytes",
+                window, YAMUX_MIN_RECEIVE_WINDOW
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
 /// Events that can be obtained from polling the Libp2p Service.
 #[derive(Debug)]
 pub enum Libp2pEvent {
     /// An RPC response request has been received on the swarm.
     RPC(PeerId, RPCEvent),
+    /// A peer's RPC request was refused, without a response, because the open substream limit
+    /// was reached.
+    InboundRefused { peer_id: PeerId, request: RPCRequest },
     /// Initiated the connection to a new peer.
     PeerDialed(PeerId),
     /// A peer has disconnected.
     PeerDisconnected(PeerId),
     /// Received pubsub message.
     PubsubMessage {
+        /// Identifies the message to `Service::validation_complete`.
+        id: u64,
         source: PeerId,
         topics: Vec<TopicHash>,
-        message:  Vec<u8>,
+        message: PubsubMessage,
     },
+    /// A diagnostic probe was received, `latency` after it was published.
+    ProbeReceived { origin: PeerId, latency: Duration },
+    /// A peer other than the one we dialed answered, and the connection was rejected.
+    PeerIdMismatch { claimed: PeerId, derived: PeerId },
+    /// Our last connected peer disconnected.
+    Isolated,
+    /// We connected to a peer after having none.
+    Deisolated,
 }
 
 /// Loads a private key from disk. If this fails, a new key is
@@ -272,9 +943,33 @@ pub enum Libp2pEvent {
 /// Currently only secp256k1 keys are allowed, as these are the only keys supported by discv5.
 fn load_private_key(config: &NetworkConfig, log: &slog::Logger) -> Keypair {
     // TODO: Currently using secp256k1 keypairs - currently required for discv5
+    #[cfg(any(test, feature = "test-utils"))]
+    {
+        if let Some(seed) = config.key_seed {
+            warn!(log, "Using a network key derived from a seed, for testing only"; "seed" => seed);
+            return seeded_key(seed);
+        }
+    }
+    load_key_file(&config.network_dir.join(NETWORK_KEY_FILENAME), log)
+}
+
+/// Derives a secp256k1 key from a seed. The same seed always gives the same key.
+#[cfg(any(test, feature = "test-utils"))]
+fn seeded_key(seed: u64) -> Keypair {
+    // a leading 1 keeps the key non-zero and well below the curve order for every seed
+    let mut key_bytes = [0u8; 32];
+    key_bytes[0] = 1;
+    key_bytes[24..].copy_from_slice(&seed.to_be_bytes());
+    let secret_key = libp2p::core::identity::secp256k1::SecretKey::from_bytes(&mut key_bytes)
+        .expect("seeded key is a valid secp256k1 secret key");
+    Keypair::Secp256k1(secret_key.into())
+}
+
+/// Loads a secp256k1 key from the given file, generating and saving a new key if it cannot be
+/// loaded.
+fn load_key_file(network_key_f: &Path, log: &slog::Logger) -> Keypair {
     // check for key from disk
-    let network_key_f = config.network_dir.join(NETWORK_KEY_FILENAME);
-    if let Ok(mut network_key_file) = File::open(network_key_f.clone()) {
+    if let Ok(mut network_key_file) = File::open(network_key_f) {
         let mut key_bytes: Vec<u8> = Vec::with_capacity(36);
         match network_key_file.read_to_end(&mut key_bytes) {
             Err(_) => debug!(log, "Could not read network key file"),
@@ -295,13 +990,21 @@ fn load_private_key(config: &NetworkConfig, log: &slog::Logger) -> Keypair {
 
     // if a key could not be loaded from disk, generate a new one and save it
     let local_private_key = Keypair::generate_secp256k1();
-    if let Keypair::Secp256k1(key) = local_private_key.clone() {
-        let _ = std::fs::create_dir_all(&config.network_dir);
-        match File::create(network_key_f.clone())
+    save_key_file(network_key_f, &local_private_key, log);
+    local_private_key
+}
+
+/// Writes a secp256k1 key to the given file. Other key types are not saved.
+fn save_key_file(network_key_f: &Path, local_private_key: &Keypair, log: &slog::Logger) {
+    if let Keypair::Secp256k1(key) = local_private_key {
+        if let Some(key_dir) = network_key_f.parent() {
+            let _ = std::fs::create_dir_all(key_dir);
+        }
+        match File::create(network_key_f)
             .and_then(|mut f| f.write_all(&key.secret().to_bytes()))
         {
             Ok(_) => {
-                debug!(log, "New network key generated and written to disk");
+                debug!(log, "Network key written to disk");
             }
             Err(e) => {
                 warn!(
@@ -311,5 +1014,400 @@ fn load_private_key(config: &NetworkConfig, log: &slog::Logger) -> Keypair {
             }
         }
     }
-    local_private_key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::discovery::{ConnectionEvent, DisconnectReason};
+    use crate::test_utils::*;
+    use tokio::runtime::current_thread::Runtime;
+
+    const TEST_TOPIC: &str = "/mothra/test";
+
+    /// Starts a node subscribed to `TEST_TOPIC` that dials `config`'s node.
+    fn subscriber_of(config: &NetworkConfig) -> (Service, sync::Receiver<Message>) {
+        let mut subscriber_config = test_config();
+        subscriber_config.topics = vec![TEST_TOPIC.to_string()];
+        subscriber_config.libp2p_nodes = vec![listen_addr(config)];
+        test_service(subscriber_config)
+    }
+
+    #[test]
+    fn gossip_is_authored_by_the_gossip_key() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut config = test_config();
+        config.gossip_key_file = Some(config.network_dir.join("gossip_key"));
+        let (mut publisher, _publisher_rx) = test_service(config.clone());
+        let (mut subscriber, _subscriber_rx) = subscriber_of(&config);
+        let gossip_author = publisher
+            .gossip_key
+            .as_ref()
+            .expect("gossip key loaded")
+            .public()
+            .into_peer_id();
+        assert_ne!(&gossip_author, publisher.local_peer_id());
+
+        // the publisher is not subscribed to the topic itself, so it publishes to the subscriber
+        // as soon as it learns of the subscription, without waiting for a mesh
+        let subscriber_id = subscriber.local_peer_id().clone();
+        let topic = Topic::new(TEST_TOPIC.to_string());
+        poll_until(&mut runtime, &mut [&mut publisher, &mut subscriber], |services, _| {
+            services[0]
+                .swarm
+                .peer_topics(&subscriber_id)
+                .map_or(false, |topics| topics.contains(&topic.no_hash()))
+        });
+        publisher
+            .swarm
+            .publish(vec![topic], b"authored".to_vec())
+            .expect("message reaches the subscriber");
+
+        let events = poll_until(&mut runtime, &mut [&mut publisher, &mut subscriber], |_, events| {
+            events.iter().any(|(index, event)| match event {
+                Libp2pEvent::PubsubMessage { .. } => *index == 1,
+                _ => false,
+            })
+        });
+        let source = events
+            .into_iter()
+            .filter_map(|(_, event)| match event {
+                Libp2pEvent::PubsubMessage { source, .. } => Some(source),
+                _ => None,
+            })
+            .next()
+            .expect("subscriber received the message");
+        assert_eq!(source, gossip_author);
+    }
+
+    #[test]
+    fn rotated_identity_keeps_application_state() {
+        let mut runtime = Runtime::new().unwrap();
+        let config = test_config();
+        let (mut service, _rx) = test_service(config.clone());
+        let mut peer_config = test_config();
+        peer_config.libp2p_nodes = vec![listen_addr(&config)];
+        let (mut peer, _peer_rx) = test_service(peer_config);
+        let peer_id = peer.local_peer_id().clone();
+        poll_until(&mut runtime, &mut [&mut service, &mut peer], |services, _| {
+            services[0].swarm.connected_peers().contains(&peer_id)
+        });
+        let old_peer_id = service.local_peer_id().clone();
+        let banned = PeerId::random();
+        service.swarm.ban_peer(banned.clone());
+        assert!(service.swarm.subscribe(Topic::new(TEST_TOPIC.to_string())));
+
+        service
+            .rotate_identity(Keypair::generate_secp256k1())
+            .expect("identity rotated");
+        let new_peer_id = service.local_peer_id().clone();
+        assert_ne!(new_peer_id, old_peer_id);
+        assert!(service.swarm.is_banned(&banned));
+        assert!(service
+            .swarm
+            .subscriptions()
+            .contains(&Topic::new(TEST_TOPIC.to_string()).no_hash()));
+
+        // the old identity's connections are reported closed
+        assert!(service.swarm.connected_peers().is_empty());
+        assert!(service.recent_connection_events().iter().any(|entry| {
+            entry.peer_id == peer_id
+                && entry.event == ConnectionEvent::Disconnected(DisconnectReason::IdentityRotated)
+        }));
+
+        // the new swarm listens on the same port under the new identity
+        Swarm::dial_addr(&mut peer.swarm, listen_addr(&service.config)).expect("dialing starts");
+        let events = poll_until(&mut runtime, &mut [&mut service, &mut peer], |_, events| {
+            events.iter().any(|(index, event)| match event {
+                Libp2pEvent::PeerDialed(dialed) => *index == 1 && *dialed == new_peer_id,
+                _ => false,
+            })
+        });
+        assert!(saw_disconnect(&events, 0, &peer_id));
+    }
+
+    #[test]
+    fn failed_rotation_keeps_the_current_identity() {
+        let mut runtime = Runtime::new().unwrap();
+        let config = test_config();
+        let (mut service, _rx) = test_service(config.clone());
+        let mut peer_config = test_config();
+        peer_config.libp2p_nodes = vec![listen_addr(&config)];
+        let (mut peer, _peer_rx) = test_service(peer_config);
+        let peer_id = peer.local_peer_id().clone();
+        poll_until(&mut runtime, &mut [&mut service, &mut peer], |services, _| {
+            services[0].swarm.connected_peers().contains(&peer_id)
+        });
+
+        // a reserved ENR field makes building the new behaviour fail
+        let old_peer_id = service.local_peer_id().clone();
+        service
+            .config
+            .custom_enr_fields
+            .insert("id".to_string(), b"v5".to_vec());
+        assert!(service.rotate_identity(Keypair::generate_secp256k1()).is_err());
+        assert_eq!(service.local_peer_id(), &old_peer_id);
+        assert!(service.swarm.connected_peers().contains(&peer_id));
+    }
+
+    #[test]
+    fn rotation_rejects_keys_discovery_cannot_use() {
+        let (mut service, _rx) = test_service(test_config());
+        let old_peer_id = service.local_peer_id().clone();
+        assert!(service.rotate_identity(Keypair::generate_ed25519()).is_err());
+        assert_eq!(service.local_peer_id(), &old_peer_id);
+    }
+
+    #[test]
+    fn snapshot_diff_shows_a_new_subscription() {
+        let (mut service, _rx) = test_service(test_config());
+        let before = service.snapshot();
+        assert!(service.swarm.subscribe(Topic::new(TEST_TOPIC.to_string())));
+        let banned = PeerId::random();
+        service.swarm.ban_peer(banned.clone());
+
+        let diff = before.diff(&service.snapshot());
+        assert_eq!(diff.subscriptions_added.len(), 1);
+        assert!(diff.subscriptions_added.contains(TEST_TOPIC));
+        assert!(diff.banned_added.contains(&banned.to_base58()));
+        assert!(diff.peers_added.is_empty());
+        assert!(diff.subscriptions_removed.is_empty());
+    }
+
+    #[test]
+    fn yamux_receive_window_below_the_minimum_is_an_error() {
+        let mut config = test_config();
+        config.transport_buffers.yamux_receive_window = Some(YAMUX_MIN_RECEIVE_WINDOW - 1);
+        let (tx, _rx) = sync::sync_channel(1);
+        assert!(Service::new(config, std::sync::Mutex::new(tx), null_logger()).is_err());
+    }
+
+    #[test]
+    fn custom_transport_buffers_carry_messages() {
+        let mut runtime = Runtime::new().unwrap();
+        let buffers = TransportBuffers {
+            tcp_send_buffer_size: Some(64 * 1024),
+            tcp_recv_buffer_size: Some(64 * 1024),
+            yamux_receive_window: Some(YAMUX_MIN_RECEIVE_WINDOW),
+            mplex_max_buffer_size: Some(16),
+        };
+        let mut config = test_config();
+        config.transport_buffers = buffers;
+        let (mut publisher, _publisher_rx) = test_service(config.clone());
+        let mut subscriber_config = test_config();
+        subscriber_config.transport_buffers = buffers;
+        subscriber_config.topics = vec![TEST_TOPIC.to_string()];
+        subscriber_config.libp2p_nodes = vec![listen_addr(&config)];
+        let (mut subscriber, _subscriber_rx) = test_service(subscriber_config);
+
+        let subscriber_id = subscriber.local_peer_id().clone();
+        let topic = Topic::new(TEST_TOPIC.to_string());
+        poll_until(&mut runtime, &mut [&mut publisher, &mut subscriber], |services, _| {
+            services[0]
+                .swarm
+                .peer_topics(&subscriber_id)
+                .map_or(false, |topics| topics.contains(&topic.no_hash()))
+        });
+        // larger than a single default TCP segment, so it spans several frames
+        let payload = vec![7u8; 100 * 1024];
+        publisher
+            .swarm
+            .publish(vec![topic], payload.clone())
+            .expect("message reaches the subscriber");
+        let events = poll_until(&mut runtime, &mut [&mut publisher, &mut subscriber], |_, events| {
+            events.iter().any(|(index, event)| match event {
+                Libp2pEvent::PubsubMessage { .. } => *index == 1,
+                _ => false,
+            })
+        });
+        let received = events
+            .into_iter()
+            .filter_map(|(_, event)| match event {
+                Libp2pEvent::PubsubMessage { message, .. } => Some(message),
+                _ => None,
+            })
+            .next()
+            .expect("subscriber received the message");
+        assert_eq!(received.data(), &payload[..]);
+    }
+
+    #[test]
+    fn seeded_keys_give_stable_peer_ids() {
+        let peer_id = |seed| PeerId::from(seeded_key(seed).public());
+        assert_eq!(peer_id(1), peer_id(1));
+        assert_eq!(peer_id(2), peer_id(2));
+        assert_ne!(peer_id(1), peer_id(2));
+
+        let mut config = test_config();
+        config.key_seed = Some(1);
+        let (service, _rx) = test_service(config);
+        assert_eq!(service.local_peer_id(), &peer_id(1));
+    }
+
+    /// Whether `index` has been told that `peer_id` disconnected.
+    fn saw_disconnect(events: &[(usize, Libp2pEvent)], index: usize, peer_id: &PeerId) -> bool {
+        events.iter().any(|(event_index, event)| match event {
+            Libp2pEvent::PeerDisconnected(disconnected) => {
+                *event_index == index && disconnected == peer_id
+            }
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn connections_over_the_peer_limit_are_evicted() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut config = test_config();
+        config.max_peers = 1;
+        config.eviction_strategy = Some(EvictionStrategy::Newest);
+        let (mut node, _rx) = test_service(config.clone());
+        let node_id = node.local_peer_id().clone();
+        let mut first_config = test_config();
+        first_config.libp2p_nodes = vec![listen_addr(&config)];
+        let (mut first, _first_rx) = test_service(first_config);
+        let first_id = first.local_peer_id().clone();
+        poll_until(&mut runtime, &mut [&mut node, &mut first], |services, _| {
+            services[0].swarm.connected_peers().contains(&first_id)
+        });
+
+        let mut second_config = test_config();
+        second_config.libp2p_nodes = vec![listen_addr(&config)];
+        let (mut second, _second_rx) = test_service(second_config);
+        poll_until(&mut runtime, &mut [&mut node, &mut first, &mut second], |_, events| {
+            saw_disconnect(events, 2, &node_id)
+        });
+        let connected: Vec<PeerId> = node.swarm.connected_peers().iter().cloned().collect();
+        assert_eq!(connected, vec![first_id]);
+    }
+
+    /// Dials a fresh peer from a node that rejects the connection, checking that the dial is
+    /// never reported.
+    fn assert_rejected_dial_is_not_reported(
+        mut node: Service,
+        reject: impl FnOnce(&mut Service, &PeerId),
+    ) {
+        let mut runtime = Runtime::new().unwrap();
+        let node_id = node.local_peer_id().clone();
+        let peer_config = test_config();
+        let (mut peer, _peer_rx) = test_service(peer_config.clone());
+        let peer_id = peer.local_peer_id().clone();
+        reject(&mut node, &peer_id);
+
+        node.swarm.dial(listen_addr(&peer_config));
+        let events = poll_until(&mut runtime, &mut [&mut node, &mut peer], |_, events| {
+            saw_disconnect(events, 1, &node_id)
+        });
+        assert!(!events.iter().any(|(index, event)| match event {
+            Libp2pEvent::PeerDialed(dialed) => *index == 0 && *dialed == peer_id,
+            _ => false,
+        }));
+        assert!(!node.swarm.connected_peers().contains(&peer_id));
+    }
+
+    #[test]
+    fn banned_peers_are_disconnected_without_being_reported() {
+        let (node, _rx) = test_service(test_config());
+        assert_rejected_dial_is_not_reported(node, |node, peer_id| {
+            node.swarm.ban_peer(peer_id.clone())
+        });
+    }
+
+    #[test]
+    fn peers_dialed_while_draining_are_disconnected_without_being_reported() {
+        let (node, _rx) = test_service(test_config());
+        assert_rejected_dial_is_not_reported(node, |node, _| node.swarm.set_draining(true));
+    }
+
+    #[test]
+    fn peers_over_the_subnet_limit_are_disconnected_without_being_reported() {
+        let mut config = test_config();
+        config.max_peers_per_subnet = Some(0);
+        let (node, _rx) = test_service(config);
+        assert_rejected_dial_is_not_reported(node, |_, _| ());
+    }
+
+    #[test]
+    fn discovery_resuming_at_or_above_its_pause_is_an_error() {
+        let mut config = test_config();
+        config.discovery_pause_events = 10;
+        config.discovery_resume_events = 10;
+        let (tx, _rx) = sync::sync_channel(1);
+        assert!(Service::new(config, std::sync::Mutex::new(tx), null_logger()).is_err());
+    }
+
+    #[test]
+    fn undelivered_messages_pause_discovery_until_the_application_catches_up() {
+        let clock = MockClock::new();
+        let mut config = test_config();
+        config.discovery_pause_events = 2;
+        config.discovery_resume_events = 1;
+        let (mut service, rx) = test_service_with_capacity(config, 1);
+        run_with_clock(&clock, || {
+            for value in 0..4u8 {
+                service.deliver(Message::new(GOSSIP.to_string(), String::new(), 0, String::new(), vec![value]));
+            }
+            // one message fits in the channel and three wait
+            assert!(service.swarm.discovery_paused());
+
+            let mut received = vec![rx.try_recv().unwrap().value[0]];
+            clock.advance(DELIVERY_RETRY_INTERVAL);
+            service.poll_undelivered();
+            assert!(service.swarm.discovery_paused());
+
+            received.push(rx.try_recv().unwrap().value[0]);
+            clock.advance(DELIVERY_RETRY_INTERVAL);
+            service.poll_undelivered();
+            assert!(!service.swarm.discovery_paused());
+
+            while received.len() < 4 {
+                received.push(rx.try_recv().unwrap().value[0]);
+                service.poll_undelivered();
+            }
+            assert_eq!(received, vec![0, 1, 2, 3]);
+            assert!(service.undelivered.is_empty());
+        });
+    }
+
+    #[test]
+    fn an_undelivered_message_limit_below_the_discovery_pause_is_an_error() {
+        let mut config = test_config();
+        config.discovery_pause_events = 10;
+        config.discovery_resume_events = 5;
+        config.max_undelivered_events = 9;
+        let (tx, _rx) = sync::sync_channel(1);
+        assert!(Service::new(config, std::sync::Mutex::new(tx), null_logger()).is_err());
+    }
+
+    #[test]
+    fn messages_beyond_the_undelivered_limit_are_dropped() {
+        let clock = MockClock::new();
+        let mut config = test_config();
+        config.discovery_pause_events = 2;
+        config.discovery_resume_events = 1;
+        config.max_undelivered_events = 2;
+        let (mut service, rx) = test_service_with_capacity(config, 1);
+        run_with_clock(&clock, || {
+            // one message fits in the channel, two wait and the last is dropped
+            let kept: Vec<bool> = (0..4u8)
+                .map(|value| {
+                    service.deliver(Message::new(GOSSIP.to_string(), String::new(), 0, String::new(), vec![value]))
+                })
+                .collect();
+            assert_eq!(kept, vec![true, true, true, false]);
+            assert_eq!(
+                service.swarm.dropped_message_stats().get(&DropReason::DeliveryBacklogFull),
+                Some(&1)
+            );
+
+            let mut received = Vec::new();
+            while received.len() < 3 {
+                received.push(rx.try_recv().unwrap().value[0]);
+                clock.advance(DELIVERY_RETRY_INTERVAL);
+                service.poll_undelivered();
+            }
+            assert_eq!(received, vec![0, 1, 2]);
+            assert!(rx.try_recv().is_err());
+        });
+    }
 }
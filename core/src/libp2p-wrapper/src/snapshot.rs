@@ -0,0 +1,150 @@
+//! Captures the observable state of the network service so that tests can assert on what an
+//! operation changed.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The observable state of a `Service` at a point in time.
+///
+/// Gossipsub does not expose its mesh, so mesh membership is not captured.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkSnapshot {
+    /// The connected peers.
+    pub peers: BTreeSet<String>,
+    /// The gossipsub topics we are subscribed to.
+    pub subscriptions: BTreeSet<String>,
+    /// The peers that have been banned.
+    pub banned: BTreeSet<String>,
+}
+
+impl NetworkSnapshot {
+    /// The changes needed to get from this snapshot to `other`.
+    pub fn diff(&self, other: &NetworkSnapshot) -> NetworkDiff {
+        NetworkDiff {
+            peers_added: other.peers.difference(&self.peers).cloned().collect(),
+            peers_removed: self.peers.difference(&other.peers).cloned().collect(),
+            subscriptions_added: other
+                .subscriptions
+                .difference(&self.subscriptions)
+                .cloned()
+                .collect(),
+            subscriptions_removed: self
+                .subscriptions
+                .difference(&other.subscriptions)
+                .cloned()
+                .collect(),
+            banned_added: other.banned.difference(&self.banned).cloned().collect(),
+            banned_removed: self.banned.difference(&other.banned).cloned().collect(),
+        }
+    }
+}
+
+/// The difference between two `NetworkSnapshot`s.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkDiff {
+    pub peers_added: BTreeSet<String>,
+    pub peers_removed: BTreeSet<String>,
+    pub subscriptions_added: BTreeSet<String>,
+    pub subscriptions_removed: BTreeSet<String>,
+    pub banned_added: BTreeSet<String>,
+    pub banned_removed: BTreeSet<String>,
+}
+
+impl NetworkDiff {
+    /// Whether the snapshots were identical.
+    pub fn is_empty(&self) -> bool {
+        self.peers_added.is_empty()
+            && self.peers_removed.is_empty()
+            && self.subscriptions_added.is_empty()
+            && self.subscriptions_removed.is_empty()
+            && self.banned_added.is_empty()
+            && self.banned_removed.is_empty()
+    }
+}
+
+/// Our view of the network as a graph, for export to visualisation tools. Nodes are peers,
+/// including ourselves, and topics. Peers are joined to the peers they are connected to and to
+/// the topics they subscribe to.
+///
+/// Gossipsub does not expose its mesh, so mesh membership is not included.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TopologyGraph {
+    /// Our own peer id.
+    pub local_peer: String,
+    /// The peers we are connected to.
+    pub peers: BTreeSet<String>,
+    /// The topics we or our peers subscribe to.
+    pub topics: BTreeSet<String>,
+    /// The connections and subscriptions joining peers and topics.
+    pub edges: BTreeSet<TopologyEdge>,
+}
+
+impl TopologyGraph {
+    /// Adds a peer's subscriptions to the graph.
+    pub(crate) fn add_subscriptions<'a>(
+        &mut self,
+        peer: &str,
+        topics: impl Iterator<Item = &'a str>,
+    ) {
+        for topic in topics {
+            self.topics.insert(topic.to_string());
+            self.edges.insert(TopologyEdge {
+                from: peer.to_string(),
+                to: topic.to_string(),
+                kind: TopologyEdgeKind::Subscription,
+            });
+        }
+    }
+}
+
+/// An edge of a `TopologyGraph`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TopologyEdge {
+    /// The peer the edge starts from.
+    pub from: String,
+    /// The peer or topic the edge ends at.
+    pub to: String,
+    /// What the edge represents.
+    pub kind: TopologyEdgeKind,
+}
+
+/// What a `TopologyEdge` represents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TopologyEdgeKind {
+    /// The peers are connected.
+    Connection,
+    /// The peer subscribes to the topic.
+    Subscription,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(items: &[&str]) -> BTreeSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn diff_lists_what_changed() {
+        let before = NetworkSnapshot {
+            peers: set(&["a", "b"]),
+            subscriptions: set(&["/topic/1"]),
+            banned: set(&[]),
+        };
+        let after = NetworkSnapshot {
+            peers: set(&["b", "c"]),
+            subscriptions: set(&["/topic/1", "/topic/2"]),
+            banned: set(&["a"]),
+        };
+        let diff = before.diff(&after);
+        assert_eq!(diff.peers_added, set(&["c"]));
+        assert_eq!(diff.peers_removed, set(&["a"]));
+        assert_eq!(diff.subscriptions_added, set(&["/topic/2"]));
+        assert!(diff.subscriptions_removed.is_empty());
+        assert_eq!(diff.banned_added, set(&["a"]));
+        assert!(diff.banned_removed.is_empty());
+        assert!(!diff.is_empty());
+        assert!(after.diff(&after).is_empty());
+    }
+}
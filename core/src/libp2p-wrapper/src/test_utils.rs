@@ -0,0 +1,142 @@
+//! Helpers shared by the crate's tests.
+
+use crate::behaviour::{Behaviour, NegotiatedMultiplexers};
+use crate::clock::MockClock;
+use crate::discovery::DialExpectations;
+use crate::service::{Libp2pEvent, Message, Service};
+use crate::{Multiaddr, NetworkConfig};
+use futures::prelude::*;
+use futures::future;
+use libp2p::core::identity::Keypair;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::nodes::Substream;
+use std::net::{TcpListener, UdpSocket};
+use std::sync::mpsc as sync;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::runtime::current_thread::{self, Runtime};
+use tokio_timer::clock::Clock as RuntimeClock;
+use tokio_timer::Timeout;
+
+/// How long network tests wait for a condition. Gossipsub's first heartbeat is five seconds
+/// after start, so this leaves room for a mesh to form.
+const NETWORK_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `f` as a task on a single-threaded runtime driven by `clock`, so that it can create
+/// timers and reads the mock time through `clock::now`.
+pub fn run_with_clock<F, R>(clock: &MockClock, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let mut runtime = current_thread::Builder::new()
+        .clock(RuntimeClock::new_with_now(clock.clone()))
+        .build()
+        .expect("test runtime builds");
+    runtime
+        .block_on(future::lazy(move || Ok::<_, ()>(f())))
+        .expect("test task completes")
+}
+
+/// A logger that discards everything.
+pub fn null_logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, slog::o!())
+}
+
+/// A configuration for a node on free local ports, with a network directory of its own.
+pub fn test_config() -> NetworkConfig {
+    let port = free_port();
+    let mut network_dir = std::env::temp_dir();
+    network_dir.push(format!("mothra-test-{}-{}", std::process::id(), port));
+    NetworkConfig {
+        network_dir,
+        libp2p_port: port,
+        discovery_port: port,
+        ..NetworkConfig::default()
+    }
+}
+
+/// A port that is free for both TCP and UDP on the loopback address.
+fn free_port() -> u16 {
+    loop {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("binds a UDP port");
+        let port = socket.local_addr().expect("UDP socket has an address").port();
+        if TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
+/// The address a node built from `config` listens on.
+pub fn listen_addr(config: &NetworkConfig) -> Multiaddr {
+    format!("/ip4/127.0.0.1/tcp/{}", config.libp2p_port)
+        .parse()
+        .expect("valid multiaddr")
+}
+
+/// The substream type the service runs its behaviour over.
+pub type TestSubstream = Substream<StreamMuxerBox>;
+
+/// Builds a behaviour with a new identity, outside of any swarm so that tests can drive it
+/// directly.
+pub fn test_behaviour(config: &NetworkConfig) -> Behaviour<TestSubstream> {
+    let key = Keypair::generate_secp256k1();
+    Behaviour::new(
+        &key,
+        &key,
+        config,
+        DialExpectations::default(),
+        NegotiatedMultiplexers::default(),
+        &null_logger(),
+    )
+    .expect("behaviour builds")
+}
+
+/// Starts a service, returning it with the receiver of the messages it passes to the
+/// application.
+pub fn test_service(config: NetworkConfig) -> (Service, sync::Receiver<Message>) {
+    test_service_with_capacity(config, 1024)
+}
+
+/// Starts a service whose channel to the application holds `capacity` messages.
+pub fn test_service_with_capacity(
+    config: NetworkConfig,
+    capacity: usize,
+) -> (Service, sync::Receiver<Message>) {
+    let (tx, rx) = sync::sync_channel(capacity);
+    let service = Service::new(config, Mutex::new(tx), null_logger()).expect("service starts");
+    (service, rx)
+}
+
+/// Polls the services on `runtime` until `done` holds, and returns the events they produced,
+/// paired with the index of the producing service. `done` is checked after each round of
+/// polling, with every event produced so far. Panics if it does not hold in time.
+pub fn poll_until<F>(
+    runtime: &mut Runtime,
+    services: &mut [&mut Service],
+    mut done: F,
+) -> Vec<(usize, Libp2pEvent)>
+where
+    F: FnMut(&mut [&mut Service], &[(usize, Libp2pEvent)]) -> bool,
+{
+    let mut events = Vec::new();
+    let rounds = future::poll_fn(|| -> Poll<(), ()> {
+        for (index, service) in services.iter_mut().enumerate() {
+            loop {
+                match Stream::poll(&mut **service) {
+                    Ok(Async::Ready(Some(event))) => events.push((index, event)),
+                    Ok(Async::Ready(None)) | Err(_) => return Err(()),
+                    Ok(Async::NotReady) => break,
+                }
+            }
+        }
+        if done(&mut *services, &events) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    });
+    runtime
+        .block_on(Timeout::new(rounds, NETWORK_TEST_TIMEOUT))
+        .expect("condition holds before the timeout");
+    events
+}
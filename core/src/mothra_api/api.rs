@@ -12,7 +12,7 @@ use tokio::timer::Interval;
 use tokio_timer::clock::Clock;
 use futures::Future;
 use clap::{App, Arg, AppSettings};
-use libp2p_wrapper::{NetworkConfig, Topic, BEACON_ATTESTATION_TOPIC, BEACON_BLOCK_TOPIC,Message,GOSSIP,RPC,RPCRequest,RPCResponse,RPCErrorResponse,RPCEvent,PeerId};
+use libp2p_wrapper::{NetworkConfig, Topic, BEACON_ATTESTATION_TOPIC, BEACON_BLOCK_TOPIC,Message,GOSSIP,RPC,VALIDATION,RPCRequest,RPCResponse,RPCErrorResponse,RPCEvent,PeerId};
 use tokio::sync::mpsc;
 use super::network::{Network,NetworkMessage,OutgoingMessage};
 
@@ -22,6 +22,10 @@ pub const HEARTBEAT_INTERVAL_SECONDS: u64 = 10;
 /// Create a warning log whenever the peer count is at or below this value.
 pub const WARN_PEER_COUNT: usize = 1;
 
+/// The number of messages from the network waiting to be passed on to the application before
+/// the network service holds further messages back.
+const NETWORK_CHANNEL_CAPACITY: usize = 64;
+
 pub fn start(args: ArgMatches, local_tx: &sync::Sender<Message>,local_rx: &sync::Receiver<Message>, log: slog::Logger) {
     info!(log,"Initializing libP2P....");
     let runtime = Builder::new()
@@ -33,7 +37,7 @@ pub fn start(args: ArgMatches, local_tx: &sync::Sender<Message>,local_rx: &sync:
     let mut network_config = NetworkConfig::new();
     network_config.apply_cli_args(&args).unwrap();
     let network_logger = log.new(o!("Network" => "Network"));
-    let (network_tx, network_rx) = sync::channel();
+    let (network_tx, network_rx) = sync::sync_channel(NETWORK_CHANNEL_CAPACITY);
     let (network, network_send) = Network::new(
             network_tx,
             &network_config,
@@ -59,6 +63,9 @@ pub fn start(args: ArgMatches, local_tx: &sync::Sender<Message>,local_rx: &sync:
                         rpc_response(network_send.clone(),local_message.command,local_message.peer,local_message.value.to_vec(),log.new(o!("API" => "rpc()")));
                     }
                 }
+                else if local_message.category == VALIDATION.to_string(){
+                    validation_complete(network_send.clone(),local_message.id,log.new(o!("API" => "validation_complete()")));
+                }
             }
             Err(_) => {
                 
@@ -94,9 +101,12 @@ fn monitor(
 
     let heartbeat = move |_| {
 
-        let connected_peer_count = libp2p.lock().swarm.num_connected_peers();
+        let (connected_peer_count, known_peer_count) = {
+            let libp2p = libp2p.lock();
+            (libp2p.swarm.num_connected_peers(), libp2p.known_peer_count())
+        };
 
-        debug!(log, "libp2p"; "peer_count" => connected_peer_count);
+        debug!(log, "libp2p"; "peer_count" => connected_peer_count, "known_peer_count" => known_peer_count);
 
         if connected_peer_count <= WARN_PEER_COUNT {
             warn!(log, "Low libp2p peer count"; "peer_count" => connected_peer_count);
@@ -125,6 +135,16 @@ fn gossip( mut network_send: mpsc::UnboundedSender<NetworkMessage>, topic: Strin
                 });
 }
 
+fn validation_complete( mut network_send: mpsc::UnboundedSender<NetworkMessage>, id: u64, log: slog::Logger){
+    network_send.try_send(NetworkMessage::ValidationComplete(id))
+                .unwrap_or_else(|_| {
+                    warn!(
+                        log,
+                        "Could not send validation result."
+                    )
+                });
+}
+
 fn rpc_request( mut network_send: mpsc::UnboundedSender<NetworkMessage>, method: String, peer: String, data: Vec<u8>, log: slog::Logger){
     // use 0 as the default request id, when an ID is not required.
     let request_id: usize = 0;
@@ -215,6 +235,13 @@ pub fn config(args: Vec<String>) -> ArgMatches<'static> {
             .help("The IP address to broadcast to other peers on how to reach this node.")
             .takes_value(true),
     )
+    .arg(
+        Arg::with_name("gossip-key")
+            .long("gossip-key")
+            .value_name("FILE")
+            .help("A key file used to author gossip messages, separate from the node identity. Generated if it does not exist.")
+            .takes_value(true),
+    )
     .arg(
         Arg::with_name("topics")
             .long("topics")
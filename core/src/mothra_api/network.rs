@@ -4,7 +4,7 @@ use libp2p_wrapper::Service as LibP2PService;
 use libp2p_wrapper::{Message,RPC,GOSSIP,DISCOVERY};
 use libp2p_wrapper::{Libp2pEvent, PeerId};
 use libp2p_wrapper::{RPCEvent,RPCRequest,RPCResponse,RPCErrorResponse};
-use libp2p_wrapper::Topic;
+use libp2p_wrapper::{PublishError, Topic};
 use futures::prelude::*;
 use futures::Stream;
 use parking_lot::Mutex;
@@ -22,7 +22,7 @@ pub struct Network {
 
 impl Network {
     pub fn new(
-        tx: sync::Sender<Message>,
+        tx: sync::SyncSender<Message>,
         config: &NetworkConfig,
         executor: &TaskExecutor,
         log: slog::Logger,
@@ -31,12 +31,11 @@ impl Network {
         let (network_send, network_recv) = mpsc::unbounded_channel::<NetworkMessage>();
         // launch libp2p Network
         let libp2p_log = log.new(o!("Network" => "Libp2p"));
-        let libp2p_service = Arc::new(Mutex::new(LibP2PService::new(config.clone(), std::sync::Mutex::new(tx.clone()), libp2p_log)?));
+        let libp2p_service = Arc::new(Mutex::new(LibP2PService::new(config.clone(), std::sync::Mutex::new(tx), libp2p_log)?));
         let libp2p_exit = spawn_service(
             libp2p_service.clone(),
             network_recv,
             network_send.clone(),
-            std::sync::Mutex::new(tx),
             executor,
             log,
         )?;
@@ -58,11 +57,11 @@ fn spawn_service(
     libp2p_service: Arc<Mutex<LibP2PService>>,
     network_recv: mpsc::UnboundedReceiver<NetworkMessage>,
     network_send: mpsc::UnboundedSender<NetworkMessage>,
-    tx: std::sync::Mutex<sync::Sender<Message>>,
     executor: &TaskExecutor,
     log: slog::Logger,
 ) -> error::Result<tokio::sync::oneshot::Sender<()>> {
     let (network_exit, exit_rx) = tokio::sync::oneshot::channel();
+    let shutdown_service = libp2p_service.clone();
 
     // spawn on the current executor
     executor.spawn(
@@ -70,12 +69,13 @@ fn spawn_service(
             libp2p_service,
             network_recv,
             network_send,
-            tx,
             log.clone(),
         )
         // allow for manual termination
         .select(exit_rx.then(|_| Ok(())))
         .then(move |_| {
+            // release the network ports before the service itself is dropped
+            shutdown_service.lock().shutdown();
             info!(log.clone(), "Network shutdown");
             Ok(())
         }),
@@ -88,7 +88,6 @@ fn network_service(
     libp2p_service: Arc<Mutex<LibP2PService>>,
     mut network_recv: mpsc::UnboundedReceiver<NetworkMessage>,
     _network_send: mpsc::UnboundedSender<NetworkMessage>,
-    tx: std::sync::Mutex<sync::Sender<Message>>,
     log: slog::Logger,
 ) -> impl futures::Future<Item = (), Error = libp2p_wrapper::error::Error> {
     futures::future::poll_fn(move || -> Result<_, libp2p_wrapper::error::Error> {
@@ -104,7 +103,20 @@ fn network_service(
                     },
                     NetworkMessage::Publish { topics, message } => {
                         //debug!(log, "Sending pubsub message"; "topics" => format!("{:?}",topics));
-                        libp2p_service.lock().swarm.publish(topics, message);
+                        match libp2p_service.lock().swarm.publish(topics, message) {
+                            Ok(()) => {}
+                            Err(PublishError::Held(topic)) => {
+                                debug!(log, "Holding published message until enough peers subscribe"; "topic" => topic.as_str());
+                            }
+                            Err(e) => {
+                                warn!(log, "Published message reached no peers"; "error" => format!("{}", e));
+                            }
+                        }
+                    }
+                    NetworkMessage::ValidationComplete(id) => {
+                        if !libp2p_service.lock().validation_complete(id) {
+                            debug!(log, "Validation completed for a message not out for validation"; "id" => id);
+                        }
                     }
                 },
                 Ok(Async::NotReady) => break,
@@ -117,8 +129,9 @@ fn network_service(
             }
         }
         loop {
-            // poll the swarm
-            match libp2p_service.lock().poll() {
+            // poll the swarm, releasing the lock before handling the event
+            let poll = libp2p_service.lock().poll();
+            match poll {
                 Ok(Async::Ready(Some(event))) => match event {
                     Libp2pEvent::RPC(_peer_id, rpc_event) => {
                         //debug!(log, "RPC Event: RPC message received: {:?}", rpc_event);
@@ -126,14 +139,17 @@ fn network_service(
                             RPCEvent::Request(_, request) => {
                                 match request {
                                     RPCRequest::Message(data) => {
-                                        tx.lock().unwrap().send(Message {
+                                        libp2p_service.lock().deliver(Message {
                                             category: RPC.to_string(),
                                             command: "HELLO".to_string(),      //TODO: need to fix this when i properly package the payload
                                             req_resp: 0,
                                             peer: _peer_id.to_string(),
-                                            value: data
-                                        }).unwrap();
+                                            value: data,
+                                            id: 0,
+                                        });
                                     }
+                                    // answered by the behaviour with the application's recent item
+                                    RPCRequest::SampleRecent => {}
                                 }
                             },
                             RPCEvent::Response(id,err_response) => {
@@ -150,13 +166,14 @@ fn network_service(
                                     RPCErrorResponse::Success(response) => {
                                         match response {
                                             RPCResponse::Message(data) => {
-                                                tx.lock().unwrap().send(Message {
+                                                libp2p_service.lock().deliver(Message {
                                                     category: RPC.to_string(),
                                                     command: "HELLO".to_string(),      //TODO: need to fix this when i properly package the payload
                                                     req_resp: 1,
                                                     peer: _peer_id.to_string(),
-                                                    value: data
-                                                }).unwrap();
+                                                    value: data,
+                                                    id: 0,
+                                                });
                                             }
                                         }
                                     }
@@ -167,25 +184,42 @@ fn network_service(
                             }
                         }
                     }
+                    Libp2pEvent::InboundRefused { peer_id, request } => {
+                        warn!(log, "Refused RPC request at the open substream limit"; "peer_id" => format!("{}", peer_id), "request" => format!("{:?}", request));
+                    }
                     Libp2pEvent::PeerDialed(_peer_id) => {
-                        tx.lock().unwrap().send(Message {
+                        libp2p_service.lock().deliver(Message {
                             category: DISCOVERY.to_string(),
                             command: Default::default(),
                             req_resp: 0,
                             peer: _peer_id.to_string(),
-                            value: Default::default()
-                        }).unwrap();
+                            value: Default::default(),
+                            id: 0,
+                        });
                     }
                     Libp2pEvent::PeerDisconnected(peer_id) => {
                         debug!(log, "Peer Disconnected: {:?}", peer_id);
                     }
+                    Libp2pEvent::ProbeReceived { origin, latency } => {
+                        info!(log, "Probe received"; "origin" => format!("{}", origin), "latency_ms" => latency.as_millis() as u64);
+                    }
+                    Libp2pEvent::PeerIdMismatch { claimed, derived } => {
+                        warn!(log, "Rejected connection from unexpected peer"; "expected" => format!("{}", claimed), "actual" => format!("{}", derived));
+                    }
+                    Libp2pEvent::Isolated => {
+                        warn!(log, "Node is isolated, no connected peers");
+                    }
+                    Libp2pEvent::Deisolated => {
+                        info!(log, "Node is no longer isolated");
+                    }
                     Libp2pEvent::PubsubMessage {
                         source: _, message: _, ..
                     } => {
 
                     } 
                 },
-                Ok(Async::Ready(None)) => unreachable!("Stream never ends"),
+                // the service ends its stream once the application has gone
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
                 Ok(Async::NotReady) => break,
                 Err(_) => break,
             }
@@ -205,6 +239,8 @@ pub enum NetworkMessage {
         topics: Vec<Topic>,
         message: Vec<u8>,
     },
+    /// The application has validated the gossip message with the given id.
+    ValidationComplete(u64),
 }
 
 /// Type of outgoing messages that can be sent through the network Network.
@@ -45,8 +45,9 @@ public class example {
         return true;
     }
 
-    public static Boolean printGossipMessage(String topic, byte[] message){
+    public static Boolean printGossipMessage(String topic, byte[] message, Long messageId){
         System.out.println("Java: received a gossip message. " + topic + ":" + new String(message));
+        mothra.ValidationComplete(messageId);
         return true;
     }
 